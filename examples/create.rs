@@ -0,0 +1,55 @@
+extern crate torrent_peer;
+extern crate rustc_serialize;
+
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use rustc_serialize::hex::ToHex;
+
+use torrent_peer::TorrentBuilder;
+use torrent_peer::create::Version;
+
+fn main() {
+    let mut args = env::args().collect::<Vec<_>>();
+    if args.len() < 3 {
+        println!(
+            "Usage:\n\t{} {} {} {} {}...",
+            args[0],
+            "[--v2|--hybrid]",
+            "path/to/content",
+            "out.torrent",
+            "http://tracker/announce"
+        );
+    } else {
+        args.reverse();
+        args.pop();
+        let mut version = Version::V1;
+        if args.last().map_or(false, |arg| arg.starts_with("--")) {
+            match args.pop().unwrap().as_str() {
+                "--v2" => version = Version::V2,
+                "--hybrid" => version = Version::Hybrid,
+                other => println!("Unknown option {}", other),
+            }
+        }
+        let content = args.pop().unwrap();
+        let output = args.pop().unwrap();
+
+        let mut builder = TorrentBuilder::new(&content);
+        builder.set_created_by("torrent-peer-rs");
+        builder.set_version(version);
+        while let Some(tracker) = args.pop() {
+            builder.add_tier(vec![tracker]);
+        }
+        match builder.build() {
+            Ok(metainfo) => {
+                let mut file = File::create(&output).unwrap();
+                file.write_all(&metainfo.encode()).unwrap();
+                println!("{} {}", metainfo.info_hash.to_hex(), output);
+                if let Some(ref hash) = metainfo.info_hash_v2 {
+                    println!("{} v2", hash.to_hex());
+                }
+            }
+            Err(e) => println!("{}", e),
+        }
+    }
+}
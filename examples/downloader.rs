@@ -1,6 +1,7 @@
 #[macro_use]
 extern crate log;
 extern crate env_logger;
+extern crate bytes;
 extern crate futures;
 extern crate torrent_peer;
 extern crate tokio_core;
@@ -11,52 +12,59 @@ extern crate rustc_serialize;
 
 use std::io;
 use std::env;
+use std::rc::Rc;
+use std::cell::RefCell;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::collections::HashSet;
-use std::collections::HashMap;
 
+use futures::Future;
 use tokio_core::reactor::Core;
-use rustc_serialize::hex::FromHex;
-use rustc_serialize::hex::ToHex;
 
-use torrent_peer::hash::sha1;
 use torrent_peer::Client;
-
-const BLOCK_LEN: u32 = 16384;
-
-/*
-
-0E0F3256B21B0D6D2E58C2BCC79D1963D1162F6A 1307857409 2097152 624
-
-*/
-
+use torrent_peer::Encryption;
+use torrent_peer::Transport;
+use torrent_peer::Metainfo;
+use torrent_peer::{Storage, FileStorage};
+use torrent_peer::Pieces;
+use torrent_peer::ResumeData;
 
 pub struct Downloader {
     address: SocketAddr,
-    info_hash: Vec<u8>,
-    piece_len: usize,
-    piece_count: usize,
+    metainfo: Metainfo,
     requests: HashSet<(u32, u32, u32)>,
-    blocks: HashMap<(u32, u32), Vec<u8>>,
+    indices: HashSet<u32>,
+    storage: Rc<RefCell<FileStorage>>,
+    pieces: Rc<RefCell<Pieces>>,
+    dir: String,
 }
 impl Downloader {
     pub fn new(
         ip: String,
         port: u16,
-        hash: Vec<u8>,
-        total: usize,
-        piece: usize,
+        metainfo: Metainfo,
+        dir: String,
     ) -> Result<Self, io::Error> {
         let addr = format!("{}:{}", ip, port).parse().map_err(|e| {
             io::Error::new(io::ErrorKind::Other, format!("{}", e))
         })?;
+        let mut storage = FileStorage::new(&dir, &metainfo);
+        let mut pieces = Pieces::new(&metainfo);
+        let resume = PathBuf::from(&dir).join(format!("{}.resume", metainfo.name));
+        if let Ok(data) = ResumeData::load(&resume) {
+            match data.restore(&metainfo.info_hash, &mut pieces, &mut storage, &dir) {
+                Ok(rechecked) => println!("Resumed, {} pieces rechecked", rechecked),
+                Err(e) => println!("Resume data is ignored: {}", e),
+            }
+        }
         Ok(Self {
             address: addr,
-            info_hash: hash,
-            piece_len: piece,
-            piece_count: (total / piece + !!(total % piece)),
+            metainfo: metainfo,
             requests: HashSet::new(),
-            blocks: HashMap::new(),
+            indices: HashSet::new(),
+            storage: Rc::new(RefCell::new(storage)),
+            pieces: Rc::new(RefCell::new(pieces)),
+            dir: dir,
         })
     }
 
@@ -68,112 +76,83 @@ impl Downloader {
     /// enqueue piece index to downloader
     pub fn enqueue_index(&mut self, index: u32) {
         info!("Downloader.enqueue_index({})", index);
-        if self.piece_count < index as usize {
-            return;
-        }
-        let mut offset = 0;
-        let mut piece = self.piece_len as u32;
-        while piece > BLOCK_LEN {
-            info!(
-                "Downloader.requests.insert({}, {}, {})",
-                index,
-                offset,
-                BLOCK_LEN
-            );
-            self.requests.insert((index, offset, BLOCK_LEN));
-            piece -= BLOCK_LEN;
-            offset += BLOCK_LEN;
-        }
-        if piece > 0 {
-            info!(
-                "Downloader.requests.insert({}, {}, {})",
-                index,
-                offset,
-                piece
-            );
-            self.requests.insert((index, offset, piece));
+        self.indices.insert(index);
+        let pieces = self.pieces.borrow();
+        let missing = self.metainfo.blocks(index).into_iter().filter(|&(index, offset, _)| {
+            !pieces.has(index) && !pieces.is_received(index, offset)
+        });
+        for block in missing {
+            info!("Downloader.requests.insert({:?})", block);
+            self.requests.insert(block);
         }
     }
 
-    /// get vector of received indices
+    /// get vector of enqueued indices
     pub fn indices(&self) -> Vec<u32> {
-        self.blocks
-            .iter()
-            .map(|(&(idx, _), _)| idx)
-            .collect::<HashSet<u32>>()
-            .iter()
-            .map(|&idx| idx)
-            .collect()
-    }
-
-    /// load pieces from client into own storage
-    fn load(&mut self, blocks: &HashMap<(u32, u32), Vec<u8>>) {
-        for (&(index, offset), block) in blocks.iter() {
-            self.blocks.insert((index, offset), block.clone());
-        }
-    }
-
-    /// get next request from queue and return it to the caller
-    fn next(&mut self) -> Option<(u32, u32, u32)> {
-        if let Some(&request) = self.requests.iter().next() {
-            return Some(request);
-        }
-        None
+        self.indices.iter().cloned().collect()
     }
 
-    /// remove request from queue
-    fn complete(&mut self, request: &(u32, u32, u32)) {
-        self.requests.remove(&request);
+    /// returns true if the piece is downloaded and matches its hash
+    pub fn has(&self, index: u32) -> bool {
+        self.pieces.borrow().has(index)
     }
 
-    /// returns vector of u8 with content of the piece by index
-    pub fn piece(&mut self, index: u32) -> Option<Vec<u8>> {
-        let mut piece = Vec::new();
-        let mut offset = 0;
-        while let Some(block) = self.blocks.get_mut(&(index, offset)) {
-            piece.append(block);
-            offset += BLOCK_LEN;
-        }
-        if offset as usize == self.piece_len {
-            Some(piece)
-        } else {
-            None
-        }
+    /// stores the state to skip rehashing on the next run
+    pub fn save(&self) -> Result<(), io::Error> {
+        let resume = PathBuf::from(&self.dir).join(format!("{}.resume", self.metainfo.name));
+        let data = ResumeData::new(
+            &self.metainfo.info_hash,
+            &self.pieces.borrow(),
+            &self.dir,
+            &[self.address],
+        )?;
+        data.save(resume)
     }
 
     /// invoke downloader to get all queued indexes
     pub fn invoke(&mut self, id: &str, mut attempts: u8) -> Result<(), io::Error> {
         let mut core = Core::new().unwrap();
         let handle = core.handle();
-        let info = self.info_hash.clone();
+        let info = self.metainfo.info_hash.clone();
 
-        let mut client = core.run(Client::connect(&self.address, &handle))?;
+        let mut client = core.run(
+            Client::connect(&self.address, &handle, &info, Encryption::Enabled, Transport::Tcp),
+        )?;
 
+        client.set_storage(self.storage.clone());
+        client.set_pieces(self.pieces.clone());
+        client.set_v2(self.metainfo.info_hash_v2.is_some());
         client = core.run(client.handshake(info, id.as_bytes()))?;
-        client = core.run(client.ping())?;
-        while !self.is_done() {
+        client = core.run(client.update())?;
+        for request in self.requests.drain() {
+            client.enqueue_request(request);
+        }
+        while !client.is_done() {
+            use io::Error;
+            use io::ErrorKind::Other;
             if 0 == attempts {
-                use io::Error;
-                use io::ErrorKind::Other;
                 return Err(Error::new(Other, "Attempt limit exceeded"));
             }
-            if client.peer_choked && client.peer_intrested {
+            if client.is_banned() {
+                return Err(Error::new(Other, "Peer has sent too many bad pieces"));
+            }
+            if client.state().peer_choked && client.state().peer_intrested {
                 client = core.run(client.unchoke_peer())?;
             }
 
-            if client.am_choked {
-                client = core.run(client.unchoke_me())?;
+            if client.state().am_choked && client.state().pending.is_empty() {
+                client = core.run(client.unchoke_me().and_then(Client::update))?;
+                attempts -= 1;
+            } else if client.state().pending.is_empty() && client.next_request().is_none() {
+                // the peer has none of the requested pieces yet
+                client = core.run(client.update())?;
                 attempts -= 1;
             } else {
-                attempts += 1;
-                if let Some(request) = self.next() {
-                    client = core.run(client.download(&request))?;
-                    self.complete(&request);
-                }
+                // the session keeps the requests in flight, wait for the blocks
+                client = core.run(client.update())?;
             }
         }
-        self.load(&client.blocks);
-        Ok(())
+        self.storage.borrow_mut().flush()
     }
 }
 
@@ -182,12 +161,11 @@ fn main() {
     let mut args = env::args().collect::<Vec<_>>();
     if args.len() == 1 {
         println!(
-            "Usage:\n\t{} {} {} {} {} {}...",
+            "Usage:\n\t{} {} {} {} {}...",
             args[0],
             "192.168.0.100:6881",
-            "5E433EDAE53E68AF02BC2650E057D0FC4FE41FCD",
-            "924600668",
-            "524288",
+            "file.torrent",
+            "download/dir",
             "1"
         );
     } else {
@@ -197,10 +175,9 @@ fn main() {
         let target: Vec<_> = address.split(':').collect();
         let host = target[0].to_string();
         let port = target[1].parse::<u16>().unwrap();
-        let hash = args.pop().unwrap().as_str().from_hex().unwrap();
-        let total_len = args.pop().unwrap().parse::<usize>().unwrap();
-        let piece_len = args.pop().unwrap().parse::<usize>().unwrap();
-        let mut dl = Downloader::new(host, port, hash, total_len, piece_len).unwrap();
+        let metainfo = Metainfo::from_file(args.pop().unwrap()).unwrap();
+        let dir = args.pop().unwrap();
+        let mut dl = Downloader::new(host, port, metainfo, dir).unwrap();
 
         while let Some(index) = args.pop() {
             if let Some(index) = index.parse::<u32>().ok() {
@@ -213,10 +190,11 @@ fn main() {
             Err(e) => println!("{}", e),
         }
 
+        if let Err(e) = dl.save() {
+            println!("Resume data is not saved: {}", e);
+        }
         for index in dl.indices() {
-            if let Some(piece) = dl.piece(index) {
-                println!("{}", sha1(&piece).to_hex());
-            }
+            println!("{} {}", index, if dl.has(index) { "OK" } else { "MISSING" });
         }
     }
 }
@@ -0,0 +1,96 @@
+extern crate torrent_peer;
+extern crate tokio_core;
+extern crate rustc_serialize;
+
+use std::io;
+use std::env;
+use std::net::SocketAddr;
+use tokio_core::reactor::Core;
+use rustc_serialize::hex::ToHex;
+
+use torrent_peer::Client;
+use torrent_peer::Encryption;
+use torrent_peer::Transport;
+use torrent_peer::Metainfo;
+use torrent_peer::MagnetLink;
+use torrent_peer::ExtendedHandshake;
+
+const ATTEMPTS: u8 = 10;
+
+/// fetches the info dictionary from the peer by the metadata exchange
+fn fetch_metadata(addr: &SocketAddr, info_hash: &[u8]) -> Result<Metainfo, io::Error> {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let id = "-01-TORRENT-PEER-RS-".as_bytes();
+
+    let mut client = core.run(Client::connect(
+        addr,
+        &handle,
+        info_hash,
+        Encryption::Enabled,
+        Transport::Tcp,
+    ))?;
+    client = core.run(client.handshake(Vec::from(info_hash), id))?;
+    client = core.run(client.extended_handshake(&ExtendedHandshake::new()))?;
+    let mut attempts = ATTEMPTS;
+    while client.state().metadata.is_none() {
+        if 0 == attempts {
+            return Err(io::Error::new(io::ErrorKind::Other, "Attempt limit exceeded"));
+        }
+        if client.peer_has_metadata() {
+            client = core.run(client.request_metadata())?;
+        }
+        client = core.run(client.update())?;
+        attempts -= 1;
+    }
+    let info = client.state().metadata.clone().unwrap();
+    Metainfo::from_info(&info).map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{}", e))
+    })
+}
+
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    if args.len() != 2 {
+        println!(
+            "Usage:\n\t{} {}\n\t{} {}",
+            args[0],
+            "file.torrent",
+            args[0],
+            "'magnet:?xt=urn:btih:5E433EDAE53E68AF02BC2650E057D0FC4FE41FCD'"
+        );
+    } else if args[1].starts_with("magnet:") {
+        match MagnetLink::parse(&args[1]) {
+            Ok(link) => {
+                println!("info hash: {}", link.info_hash().to_hex());
+                println!("name:      {}", link.name.clone().unwrap_or_default());
+                for tracker in &link.trackers {
+                    println!("tracker:   {}", tracker);
+                }
+                for peer in &link.peers {
+                    println!("peer:      {}", peer);
+                }
+                for seed in &link.web_seeds {
+                    println!("web seed:  {}", seed);
+                }
+                for peer in &link.peers {
+                    match fetch_metadata(peer, link.info_hash()) {
+                        Ok(metainfo) => {
+                            for file in &metainfo.files {
+                                println!("file:      {} {}", file.path.join("/"), file.length);
+                            }
+                            break;
+                        }
+                        Err(e) => println!("{}: {}", peer, e),
+                    }
+                }
+            }
+            Err(e) => println!("{}", e),
+        }
+    } else {
+        match Metainfo::from_file(&args[1]) {
+            Ok(metainfo) => println!("{}", MagnetLink::from_metainfo(&metainfo)),
+            Err(e) => println!("{}", e),
+        }
+    }
+}
@@ -1,6 +1,7 @@
 #[macro_use]
 extern crate log;
 extern crate env_logger;
+extern crate bytes;
 extern crate futures;
 extern crate torrent_peer;
 extern crate tokio_core;
@@ -15,31 +16,30 @@ use std::net::SocketAddr;
 use std::collections::HashSet;
 use std::collections::HashMap;
 
+use bytes::Bytes;
+use futures::Future;
 use tokio_core::reactor::Core;
-use rustc_serialize::hex::FromHex;
 use rustc_serialize::hex::ToHex;
 
 use torrent_peer::hash::sha1;
 use torrent_peer::Client;
+use torrent_peer::Encryption;
+use torrent_peer::Transport;
+use torrent_peer::Metainfo;
 
-const BLOCK_LEN: u32 = 16384;
 const TRIES_TO_UNCHOKE: u8 = 5;
 
 struct PieceHandler {
     pub address: SocketAddr,
-    pub info_hash: Vec<u8>,
-    piece_len: usize,
-    piece_count: usize,
+    pub metainfo: Metainfo,
     requests: HashSet<(u32, u32, u32)>,
-    blocks: HashMap<(u32, u32), Vec<u8>>,
+    blocks: HashMap<(u32, u32), Bytes>,
 }
 impl PieceHandler {
-    pub fn new(addr: SocketAddr, hash: Vec<u8>, total: usize, piece: usize) -> Self {
+    pub fn new(addr: SocketAddr, metainfo: Metainfo) -> Self {
         Self {
             address: addr,
-            info_hash: hash,
-            piece_len: piece,
-            piece_count: (total / piece + !!(total % piece)),
+            metainfo: metainfo,
             requests: HashSet::new(),
             blocks: HashMap::new(),
         }
@@ -47,53 +47,24 @@ impl PieceHandler {
 
     pub fn add_index(&mut self, index: u32) {
         info!("PieceHandler.add_index({})", index);
-        if self.piece_count < index as usize {
-            return;
-        }
-        let mut offset = 0;
-        let mut piece = self.piece_len as u32;
-        while piece > BLOCK_LEN {
-            info!(
-                "PieceHandler.requests.insert({}, {}, {})",
-                index,
-                offset,
-                BLOCK_LEN
-            );
-            self.requests.insert((index, offset, BLOCK_LEN));
-            piece -= BLOCK_LEN;
-            offset += BLOCK_LEN;
-        }
-        if piece > 0 {
-            info!(
-                "PieceHandler::.requests.insert({}, {}, {})",
-                index,
-                offset,
-                piece
-            );
-            self.requests.insert((index, offset, piece));
+        for block in self.metainfo.blocks(index) {
+            info!("PieceHandler.requests.insert({:?})", block);
+            self.requests.insert(block);
         }
     }
 
-    pub fn load_blocks(&mut self, blocks: &HashMap<(u32, u32), Vec<u8>>) {
+    pub fn load_blocks(&mut self, blocks: &HashMap<(u32, u32), Bytes>) {
         for (&(index, offset), block) in blocks.iter() {
             self.blocks.insert((index, offset), block.clone());
         }
     }
 
-    pub fn request(&mut self) -> Option<(u32, u32, u32)> {
-        if let Some(&request) = self.requests.iter().next() {
-            self.requests.remove(&request);
-            return Some(request);
-        }
-        None
-    }
-
     pub fn get_piece(&mut self, index: u32) -> Option<Vec<u8>> {
         let mut piece = Vec::new();
         let mut offset = 0;
-        while let Some(block) = self.blocks.get_mut(&(index, offset)) {
-            piece.append(block);
-            offset += BLOCK_LEN;
+        while let Some(block) = self.blocks.remove(&(index, offset)) {
+            piece.extend_from_slice(&block);
+            offset += block.len() as u32;
         }
         if piece.len() > 0 { Some(piece) } else { None }
     }
@@ -105,8 +76,14 @@ fn download(desc: &mut PieceHandler) -> Result<(), io::Error> {
     let handle = core.handle();
     let id = "-01-TORRENT-PEER-RS-".as_bytes();
 
-    let mut client = core.run(Client::connect(&desc.address, &handle))?;
-    client = core.run(client.handshake(desc.info_hash.clone(), id))?;
+    let info = desc.metainfo.info_hash.clone();
+    let mut client = core.run(
+        Client::connect(&desc.address, &handle, &info, Encryption::Enabled, Transport::Tcp),
+    )?;
+    client = core.run(client.handshake(info, id))?;
+    for request in desc.requests.drain() {
+        client.enqueue_request(request);
+    }
     let mut attempts = TRIES_TO_UNCHOKE;
     loop {
         if 0 == attempts {
@@ -115,23 +92,25 @@ fn download(desc: &mut PieceHandler) -> Result<(), io::Error> {
                 "Attempt limit exceeded",
             ));
         }
-        if desc.requests.is_empty() {
+        if client.is_done() {
             break;
         }
-        if client.peer_choked && client.peer_intrested {
+        if client.state().peer_choked && client.state().peer_intrested {
             client = core.run(client.unchoke_peer())?;
         }
-        if client.am_choked {
-            client = core.run(client.unchoke_me())?;
+        if !client.state().pending.is_empty() {
+            // the session keeps the requests in flight, wait for the blocks
+            attempts = TRIES_TO_UNCHOKE;
+            client = core.run(client.update())?;
+        } else if client.state().am_choked {
+            client = core.run(client.unchoke_me().and_then(Client::update))?;
             attempts -= 1;
         } else {
-            attempts = TRIES_TO_UNCHOKE;
-            if let Some(request) = desc.request() {
-                client = core.run(client.request(request.0, request.1, request.2))?;
-            }
+            client = core.run(client.update())?;
+            attempts -= 1;
         }
     }
-    desc.load_blocks(&client.blocks);
+    desc.load_blocks(&client.state().blocks);
     Ok(())
 }
 
@@ -149,13 +128,11 @@ fn main() {
     if args.len() == 1 {
         //5E433EDAE53E68AF02BC2650E057D0FC4FE41FCD
         println!(
-            "Usage:\n\t{} {} {} {} {} {} {}...",
+            "Usage:\n\t{} {} {} {} {}...",
             args[0],
             "192.168.0.100",
             "6881",
-            "5E433EDAE53E68AF02BC2650E057D0FC4FE41FCD",
-            "924600668",
-            "524288",
+            "file.torrent",
             "1"
         );
     // let address = "127.0.0.1:12345".parse().unwrap();
@@ -167,13 +144,11 @@ fn main() {
         args.pop();
         let host = args.pop().unwrap();
         let port = args.pop().unwrap();
-        let hash = args.pop().unwrap();
-        let total_len = args.pop().unwrap().parse::<usize>().unwrap();
-        let piece_len = args.pop().unwrap().parse::<usize>().unwrap();
+        let path = args.pop().unwrap();
 
         let address = create_addr(host, port).unwrap();
-        let hash_info = hash.as_str().from_hex().unwrap();
-        let mut desc = PieceHandler::new(address, hash_info, total_len, piece_len);
+        let metainfo = Metainfo::from_file(path).unwrap();
+        let mut desc = PieceHandler::new(address, metainfo);
 
         let mut indices = Vec::new();
         while let Some(index) = args.pop() {
@@ -0,0 +1,50 @@
+extern crate futures;
+extern crate torrent_peer;
+
+use std::env;
+
+use futures::Stream;
+
+use torrent_peer::Metainfo;
+use torrent_peer::FileStorage;
+use torrent_peer::{Recheck, RecheckEvent};
+
+fn main() {
+    let mut args = env::args().collect::<Vec<_>>();
+    if args.len() < 3 {
+        println!(
+            "Usage:\n\t{} {} {} {}",
+            args[0],
+            "file.torrent",
+            "download/dir",
+            "[bytes per second]"
+        );
+    } else {
+        args.reverse();
+        args.pop();
+        let metainfo = Metainfo::from_file(args.pop().unwrap()).unwrap();
+        let dir = args.pop().unwrap();
+
+        let mut recheck = Recheck::new();
+        recheck.set_rate_limit(args.pop().and_then(|rate| rate.parse::<u64>().ok()));
+        let progress = recheck.start(move || FileStorage::new(&dir, &metainfo));
+        for event in progress.wait() {
+            match event {
+                Ok(RecheckEvent::Piece(index, valid, checked, total)) => {
+                    println!(
+                        "{}/{} piece {} {}",
+                        checked,
+                        total,
+                        index,
+                        if valid { "OK" } else { "BAD" }
+                    );
+                }
+                Ok(RecheckEvent::Done(bits)) => {
+                    let have = bits.iter().map(|byte| byte.count_ones()).sum::<u32>();
+                    println!("{} pieces are valid", have);
+                }
+                Err(e) => println!("{}", e),
+            }
+        }
+    }
+}
@@ -0,0 +1,73 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate torrent_peer;
+
+use std::env;
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use futures::{future, Future, Stream};
+use futures::future::Loop;
+use tokio_core::reactor::Core;
+
+use torrent_peer::Client;
+use torrent_peer::Listener;
+use torrent_peer::Metainfo;
+use torrent_peer::{Storage, FileStorage};
+use torrent_peer::Pieces;
+use torrent_peer::Recheck;
+
+fn main() {
+    let mut args = env::args().collect::<Vec<_>>();
+    if args.len() < 4 {
+        println!(
+            "Usage:\n\t{} {} {} {}...",
+            args[0],
+            "0.0.0.0:6881",
+            "download/dir",
+            "file.torrent"
+        );
+        return;
+    }
+    args.reverse();
+    args.pop();
+    let address = args.pop().unwrap().parse().unwrap();
+    let dir = args.pop().unwrap();
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let mut listener = Listener::bind(&address, &handle, b"-01-TORRENT-PEER-RS-").unwrap();
+    while let Some(path) = args.pop() {
+        let metainfo = Metainfo::from_file(path).unwrap();
+        let bits = {
+            let (dir, metainfo) = (dir.clone(), metainfo.clone());
+            Recheck::new().run(move || FileStorage::new(&dir, &metainfo)).unwrap()
+        };
+        let mut pieces = Pieces::new(&metainfo);
+        pieces.set_bitfield(&bits);
+        println!("{} has {} pieces", metainfo.name, pieces.have_count());
+        let storage: Rc<RefCell<Storage>> =
+            Rc::new(RefCell::new(FileStorage::new(&dir, &metainfo)));
+        listener.add_torrent(&metainfo, storage, Rc::new(RefCell::new(pieces)));
+    }
+
+    // every peer which is interested gets unchoked
+    let seeding = listener.incoming().for_each(|client| {
+        println!("{} is connected", client.state().addr);
+        let session = future::loop_fn(client, |client| {
+            let unchoke = {
+                let state = client.state();
+                state.peer_choked && state.peer_intrested
+            };
+            let next = if unchoke {
+                client.unchoke_peer()
+            } else {
+                client.update()
+            };
+            next.map(Loop::Continue)
+        });
+        handle.spawn(session.map(|_: Client| ()).map_err(|e| println!("{}", e)));
+        Ok(())
+    });
+    core.run(seeding).unwrap();
+}
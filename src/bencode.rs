@@ -0,0 +1,518 @@
+use std::fmt;
+use std::str;
+use std::error;
+use std::collections::BTreeMap;
+
+/// Nesting limit of lists and dictionaries, protects the stack from hostile input
+const MAX_DEPTH: usize = 256;
+
+/// Bencoded value, dictionary keys are byte strings kept in the sorted order
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl Value {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            &Value::Int(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            &Value::Bytes(ref value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// returns byte string if it is valid UTF-8
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes().and_then(|bytes| str::from_utf8(bytes).ok())
+    }
+
+    pub fn as_list(&self) -> Option<&Vec<Value>> {
+        match self {
+            &Value::List(ref value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, Value>> {
+        match self {
+            &Value::Dict(ref value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// returns value of the dictionary key, None if the key is absent or self is not a dictionary
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.as_dict().and_then(|dict| dict.get(key.as_bytes()))
+    }
+
+    /// returns canonical encoding of the value
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_to(&mut buf);
+        buf
+    }
+
+    pub fn encode_to(&self, buf: &mut Vec<u8>) {
+        match self {
+            &Value::Int(value) => {
+                buf.extend_from_slice(format!("i{}e", value).as_bytes());
+            }
+            &Value::Bytes(ref value) => {
+                buf.extend_from_slice(format!("{}:", value.len()).as_bytes());
+                buf.extend_from_slice(value);
+            }
+            &Value::List(ref list) => {
+                buf.push(b'l');
+                for value in list {
+                    value.encode_to(buf);
+                }
+                buf.push(b'e');
+            }
+            &Value::Dict(ref dict) => {
+                buf.push(b'd');
+                for (key, value) in dict {
+                    buf.extend_from_slice(format!("{}:", key.len()).as_bytes());
+                    buf.extend_from_slice(key);
+                    value.encode_to(buf);
+                }
+                buf.push(b'e');
+            }
+        }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Value {
+        Value::Int(value)
+    }
+}
+
+impl<'a> From<&'a str> for Value {
+    fn from(value: &'a str) -> Value {
+        Value::Bytes(Vec::from(value.as_bytes()))
+    }
+}
+
+impl<'a> From<&'a [u8]> for Value {
+    fn from(value: &'a [u8]) -> Value {
+        Value::Bytes(Vec::from(value))
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(value: Vec<u8>) -> Value {
+        Value::Bytes(value)
+    }
+}
+
+/// Errors of the bencode decoder, every error carries the byte offset it was found at
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum BencodeError {
+    /// input ends inside of a value
+    UnexpectedEnd(usize),
+    /// byte can't start or continue a value (offset, byte)
+    UnexpectedByte(usize, u8),
+    /// integer is malformed or not canonical
+    BadInteger(usize),
+    /// byte string length is malformed or not canonical
+    BadLength(usize),
+    /// dictionary key is not greater than the previous one (strict only)
+    UnsortedKey(usize),
+    /// something follows the top level value (strict only)
+    TrailingData(usize),
+    /// lists and dictionaries are nested too deep
+    TooDeep(usize),
+}
+
+impl BencodeError {
+    pub fn offset(&self) -> usize {
+        match self {
+            &BencodeError::UnexpectedEnd(offset) |
+            &BencodeError::UnexpectedByte(offset, _) |
+            &BencodeError::BadInteger(offset) |
+            &BencodeError::BadLength(offset) |
+            &BencodeError::UnsortedKey(offset) |
+            &BencodeError::TrailingData(offset) |
+            &BencodeError::TooDeep(offset) => offset,
+        }
+    }
+}
+
+impl fmt::Display for BencodeError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &BencodeError::UnexpectedEnd(offset) => {
+                write!(fmt, "Unexpected end of input at {}", offset)
+            }
+            &BencodeError::UnexpectedByte(offset, byte) => {
+                write!(fmt, "Unexpected byte 0x{:02X} at {}", byte, offset)
+            }
+            &BencodeError::BadInteger(offset) => write!(fmt, "Bad integer at {}", offset),
+            &BencodeError::BadLength(offset) => write!(fmt, "Bad string length at {}", offset),
+            &BencodeError::UnsortedKey(offset) => {
+                write!(fmt, "Unsorted or duplicate dictionary key at {}", offset)
+            }
+            &BencodeError::TrailingData(offset) => write!(fmt, "Trailing data at {}", offset),
+            &BencodeError::TooDeep(offset) => write!(fmt, "Nesting is too deep at {}", offset),
+        }
+    }
+}
+
+impl error::Error for BencodeError {
+    fn description(&self) -> &str {
+        match self {
+            &BencodeError::UnexpectedEnd(_) => "unexpected end of input",
+            &BencodeError::UnexpectedByte(_, _) => "unexpected byte",
+            &BencodeError::BadInteger(_) => "bad integer",
+            &BencodeError::BadLength(_) => "bad string length",
+            &BencodeError::UnsortedKey(_) => "unsorted dictionary key",
+            &BencodeError::TrailingData(_) => "trailing data",
+            &BencodeError::TooDeep(_) => "nesting is too deep",
+        }
+    }
+}
+
+/// Pulls bencoded values one by one out of the buffer
+///
+/// Strict mode (the default) accepts the canonical encoding only: no leading zeros,
+/// no negative zero, dictionary keys sorted and unique. Lenient mode accepts them and
+/// the last of duplicate keys wins.
+pub struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+    strict: bool,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Decoder {
+            data: data,
+            pos: 0,
+            strict: true,
+        }
+    }
+
+    pub fn set_strict(&mut self, enabled: bool) {
+        self.strict = enabled;
+    }
+
+    /// returns offset of the first byte which is not decoded yet
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// returns bytes which are not decoded yet
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.data[self.pos..]
+    }
+
+    /// decodes next value, returns None at the end of the input
+    pub fn next_value(&mut self) -> Result<Option<Value>, BencodeError> {
+        if self.pos == self.data.len() {
+            return Ok(None);
+        }
+        self.value(0).map(Some)
+    }
+
+    /// skips next value and returns its raw encoding
+    pub fn raw(&mut self) -> Result<&'a [u8], BencodeError> {
+        let start = self.pos;
+        self.skip(0)?;
+        Ok(&self.data[start..self.pos])
+    }
+
+    /// decodes next dictionary, its values are left raw so that they can be hashed exactly
+    pub fn raw_dict(&mut self) -> Result<BTreeMap<Vec<u8>, &'a [u8]>, BencodeError> {
+        self.expect(b'd')?;
+        let mut dict = BTreeMap::new();
+        while self.peek()? != b'e' {
+            let key = self.key(&dict)?;
+            let value = self.raw()?;
+            dict.insert(key, value);
+        }
+        self.pos += 1;
+        Ok(dict)
+    }
+
+    /// decodes next list, its values are left raw
+    pub fn raw_list(&mut self) -> Result<Vec<&'a [u8]>, BencodeError> {
+        self.expect(b'l')?;
+        let mut list = Vec::new();
+        while self.peek()? != b'e' {
+            list.push(self.raw()?);
+        }
+        self.pos += 1;
+        Ok(list)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, BencodeError> {
+        match self.peek()? {
+            b'i' => self.int().map(Value::Int),
+            b'0'..=b'9' => self.bytes().map(|bytes| Value::Bytes(Vec::from(bytes))),
+            b'l' => {
+                self.nest(depth)?;
+                self.pos += 1;
+                let mut list = Vec::new();
+                while self.peek()? != b'e' {
+                    list.push(self.value(depth + 1)?);
+                }
+                self.pos += 1;
+                Ok(Value::List(list))
+            }
+            b'd' => {
+                self.nest(depth)?;
+                self.pos += 1;
+                let mut dict = BTreeMap::new();
+                while self.peek()? != b'e' {
+                    let key = self.key(&dict)?;
+                    let value = self.value(depth + 1)?;
+                    dict.insert(key, value);
+                }
+                self.pos += 1;
+                Ok(Value::Dict(dict))
+            }
+            byte => Err(BencodeError::UnexpectedByte(self.pos, byte)),
+        }
+    }
+
+    /// validates the value without building it
+    fn skip(&mut self, depth: usize) -> Result<(), BencodeError> {
+        match self.peek()? {
+            b'i' => self.int().map(|_| ()),
+            b'0'..=b'9' => self.bytes().map(|_| ()),
+            b'l' => {
+                self.nest(depth)?;
+                self.pos += 1;
+                while self.peek()? != b'e' {
+                    self.skip(depth + 1)?;
+                }
+                self.pos += 1;
+                Ok(())
+            }
+            b'd' => {
+                self.nest(depth)?;
+                self.pos += 1;
+                let mut last: Option<&[u8]> = None;
+                while self.peek()? != b'e' {
+                    let offset = self.pos;
+                    let key = self.bytes()?;
+                    if self.strict && last.map_or(false, |last| last >= key) {
+                        return Err(BencodeError::UnsortedKey(offset));
+                    }
+                    last = Some(key);
+                    self.skip(depth + 1)?;
+                }
+                self.pos += 1;
+                Ok(())
+            }
+            byte => Err(BencodeError::UnexpectedByte(self.pos, byte)),
+        }
+    }
+
+    fn key<V>(&mut self, dict: &BTreeMap<Vec<u8>, V>) -> Result<Vec<u8>, BencodeError> {
+        let offset = self.pos;
+        match self.peek()? {
+            b'0'..=b'9' => {}
+            byte => return Err(BencodeError::UnexpectedByte(offset, byte)),
+        }
+        let key = Vec::from(self.bytes()?);
+        let sorted = dict.keys().next_back().map_or(true, |last| *last < key);
+        if self.strict && !sorted {
+            return Err(BencodeError::UnsortedKey(offset));
+        }
+        Ok(key)
+    }
+
+    fn nest(&self, depth: usize) -> Result<(), BencodeError> {
+        if depth >= MAX_DEPTH {
+            Err(BencodeError::TooDeep(self.pos))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn int(&mut self) -> Result<i64, BencodeError> {
+        // i<number>e
+        self.pos += 1;
+        let start = self.pos;
+        let digits = self.until(b'e')?;
+        let number = if digits.starts_with(b"-") {
+            &digits[1..]
+        } else {
+            digits
+        };
+        let canonical = is_number(number) && (number[0] != b'0' || digits == b"0");
+        if self.strict && !canonical {
+            return Err(BencodeError::BadInteger(start));
+        }
+        str::from_utf8(digits)
+            .ok()
+            .and_then(|digits| digits.parse::<i64>().ok())
+            .ok_or(BencodeError::BadInteger(start))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], BencodeError> {
+        // <length>:<bytes>
+        let start = self.pos;
+        let digits = self.until(b':')?;
+        if self.strict && !(is_number(digits) && (digits[0] != b'0' || digits.len() == 1)) {
+            return Err(BencodeError::BadLength(start));
+        }
+        let len = str::from_utf8(digits)
+            .ok()
+            .and_then(|digits| digits.parse::<usize>().ok())
+            .ok_or(BencodeError::BadLength(start))?;
+        if self.data.len() - self.pos < len {
+            return Err(BencodeError::UnexpectedEnd(self.data.len()));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// returns bytes up to the terminator and consumes the terminator
+    fn until(&mut self, end: u8) -> Result<&'a [u8], BencodeError> {
+        let start = self.pos;
+        match self.data[start..].iter().position(|byte| *byte == end) {
+            Some(len) => {
+                self.pos += len + 1;
+                Ok(&self.data[start..start + len])
+            }
+            None => Err(BencodeError::UnexpectedEnd(self.data.len())),
+        }
+    }
+
+    fn peek(&self) -> Result<u8, BencodeError> {
+        self.data.get(self.pos).cloned().ok_or(
+            BencodeError::UnexpectedEnd(self.pos),
+        )
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), BencodeError> {
+        match self.peek()? {
+            found if found == byte => {
+                self.pos += 1;
+                Ok(())
+            }
+            found => Err(BencodeError::UnexpectedByte(self.pos, found)),
+        }
+    }
+}
+
+fn is_number(digits: &[u8]) -> bool {
+    !digits.is_empty() && digits.iter().all(|byte| b'0' <= *byte && *byte <= b'9')
+}
+
+/// decodes the single value which shall occupy the whole input
+pub fn decode(data: &[u8]) -> Result<Value, BencodeError> {
+    let mut decoder = Decoder::new(data);
+    let value = decoder.value(0)?;
+    if decoder.position() != data.len() {
+        return Err(BencodeError::TrailingData(decoder.position()));
+    }
+    Ok(value)
+}
+
+/// decodes the first value of the input in lenient mode, the rest of the input is ignored
+pub fn decode_lenient(data: &[u8]) -> Result<Value, BencodeError> {
+    let mut decoder = Decoder::new(data);
+    decoder.set_strict(false);
+    decoder.value(0)
+}
+
+pub fn encode(value: &Value) -> Vec<u8> {
+    value.encode()
+}
+
+/// encodes dictionary of already encoded values, the counterpart of Decoder::raw_dict
+pub fn encode_raw_dict(dict: &BTreeMap<Vec<u8>, Vec<u8>>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.push(b'd');
+    for (key, value) in dict {
+        buf.extend_from_slice(format!("{}:", key.len()).as_bytes());
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(value);
+    }
+    buf.push(b'e');
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let data = b"d4:listli1ei-20e3:abce4:nestd1:a0:1:bi0ee3:numi42e3:str5:helloe";
+        let value = decode(data).unwrap();
+        assert_eq!(value.get("num").and_then(Value::as_int), Some(42));
+        assert_eq!(value.get("str").and_then(Value::as_str), Some("hello"));
+        assert_eq!(value.get("list").and_then(Value::as_list).map(Vec::len), Some(3));
+        assert_eq!(value.encode(), &data[..]);
+    }
+
+    #[test]
+    fn unsorted_keys() {
+        let data = b"d1:bi1e1:ai2ee";
+        assert_eq!(decode(data), Err(BencodeError::UnsortedKey(7)));
+        let value = decode_lenient(data).unwrap();
+        assert_eq!(value.get("a").and_then(Value::as_int), Some(2));
+        assert_eq!(value.encode(), &b"d1:ai2e1:bi1ee"[..]);
+    }
+
+    #[test]
+    fn duplicate_keys() {
+        let data = b"d1:ai1e1:ai2ee";
+        assert_eq!(decode(data), Err(BencodeError::UnsortedKey(7)));
+        assert!(decode_lenient(data).is_ok());
+    }
+
+    #[test]
+    fn leading_zeros() {
+        assert_eq!(decode(b"i03e"), Err(BencodeError::BadInteger(1)));
+        assert_eq!(decode(b"03:abc"), Err(BencodeError::BadLength(0)));
+        assert_eq!(decode_lenient(b"i03e"), Ok(Value::Int(3)));
+        assert_eq!(decode(b"i0e"), Ok(Value::Int(0)));
+        assert_eq!(decode(b"0:"), Ok(Value::Bytes(Vec::new())));
+    }
+
+    #[test]
+    fn negative_zero() {
+        assert_eq!(decode(b"i-0e"), Err(BencodeError::BadInteger(1)));
+        assert_eq!(decode(b"i-e"), Err(BencodeError::BadInteger(1)));
+        assert_eq!(decode(b"i-7e"), Ok(Value::Int(-7)));
+    }
+
+    #[test]
+    fn truncated() {
+        assert_eq!(decode(b""), Err(BencodeError::UnexpectedEnd(0)));
+        assert_eq!(decode(b"i12"), Err(BencodeError::UnexpectedEnd(3)));
+        assert_eq!(decode(b"5:abc"), Err(BencodeError::UnexpectedEnd(5)));
+        assert_eq!(decode(b"li1e"), Err(BencodeError::UnexpectedEnd(4)));
+        assert_eq!(decode(b"d1:a"), Err(BencodeError::UnexpectedEnd(4)));
+        assert_eq!(decode(b"i1ei2e"), Err(BencodeError::TrailingData(3)));
+    }
+
+    #[test]
+    fn depth_limit() {
+        let nested = |depth: usize| {
+            let mut data = vec![b'l'; depth];
+            data.extend(vec![b'e'; depth]);
+            data
+        };
+        assert!(decode(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(decode(&nested(MAX_DEPTH + 1)), Err(BencodeError::TooDeep(MAX_DEPTH)));
+        let data = nested(MAX_DEPTH + 1);
+        let mut decoder = Decoder::new(&data);
+        assert_eq!(decoder.raw(), Err(BencodeError::TooDeep(MAX_DEPTH)));
+    }
+}
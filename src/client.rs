@@ -1,94 +1,238 @@
-use Validate;
-use PeerProto;
+use PeerCodec;
 use Message;
 use Messages;
+use Reserved;
+use PeerError;
+use ExtendedHandshake;
+use Encryption;
+use MseStream;
+use extension;
+use mse::{self, MseConnection};
+use codec;
+use metadata::{self, MetadataBuffer, MetadataMessage};
+use session::{PeerSession, Timeouts};
+use listener::Slot;
+use transport;
+use Transport;
+use PeerStream;
+use Storage;
+use Pieces;
+use RequestWindow;
+use hash::{sha1, SHA256_LEN};
+use metainfo::BLOCK_LEN;
 
 use std::io;
+use std::rc::Rc;
+use std::cell::{Ref, RefCell, RefMut};
 use std::net::SocketAddr;
 use std::collections::HashSet;
 use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::time::Instant;
 
-use futures::Future;
+use bytes::Bytes;
+use futures::{future, task, Async, Future, Poll};
+use futures::task::Task;
 use tokio_core::reactor::Handle;
-use tokio_proto::pipeline::ClientService;
-use tokio_core::net::TcpStream;
-use tokio_proto::TcpClient;
-use tokio_service::Service;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::Framed;
 // use rustc_serialize::hex::ToHex;
 
 
-pub type ClientConnection = Box<Future<Item = Client, Error = io::Error>>;
+/// Blocks read from the storage ahead of sending
+const UPLOAD_QUEUE: usize = 4;
 
-pub struct Client {
-    inner: Validate<ClientService<TcpStream, PeerProto>>,
+pub type ClientConnection = Box<Future<Item = Client, Error = PeerError>>;
+
+/// State of the connection to the peer, it's updated by PeerSession as messages arrive
+pub struct PeerState {
+    pub addr: SocketAddr,
     pub am_choked: bool,
     pub am_intrested: bool,
     pub peer_choked: bool,
     pub peer_intrested: bool,
     pub peer_have: HashSet<u32>,
-    pub peer_requests: HashSet<(u32, u32, u32)>,
-    pub blocks: HashMap<(u32, u32), Vec<u8>>,
+    pub peer_have_all: bool,
+    pub peer_suggested: HashSet<u32>,
+    pub allowed_fast: HashSet<u32>,
+    pub requests: HashSet<(u32, u32, u32)>,
+    pub pending: HashSet<(u32, u32, u32)>,
+    /// requests of the peer in the order they are served
+    pub peer_requests: VecDeque<(u32, u32, u32)>,
+    /// limits number of the pending requests
+    pub window: RequestWindow,
+    /// when the pending requests were sent
+    request_times: HashMap<(u32, u32, u32), Instant>,
+    /// received blocks, kept in memory only if there is no storage
+    pub blocks: HashMap<(u32, u32), Bytes>,
+    storage: Option<Rc<RefCell<Storage>>>,
+    pieces: Option<Rc<RefCell<Pieces>>>,
+    pub hashes: HashMap<(Vec<u8>, u32, u32), Bytes>,
     pub messages: Messages,
     pub info_hash: Vec<u8>,
+    /// peer's handshake with the expected info hash is received
+    pub handshaked: bool,
+    /// extensions announced in our handshake
+    pub reserved: Reserved,
+    pub peer_reserved: Reserved,
+    pub peer_extensions: Option<ExtendedHandshake>,
+    /// bencoded info dictionary, served to peers and filled by the metadata exchange
+    pub metadata: Option<Vec<u8>>,
+    metadata_buffer: Option<MetadataBuffer>,
+    pub peer_metadata_requests: VecDeque<u32>,
+    /// messages waiting to be sent
+    pub outgoing: VecDeque<Message>,
+    /// number of the processed messages
+    pub received: u64,
+    /// number of the messages passed to the transport
+    pub sent: u64,
+    /// number of the messages passed to the transport and flushed
+    pub flushed: u64,
+    pub timeouts: Timeouts,
+    /// when the connection was established
+    pub created: Instant,
+    pub last_received: Instant,
+    pub last_sent: Instant,
+    /// slot of the listener's connection limit, it's released on close
+    pub slot: Option<Slot>,
+    /// connection is closed, no message is received or sent anymore
+    pub closed: bool,
+    /// reason of the closing, it's reported once
+    error: Option<PeerError>,
+    /// task of the session, it's woken up when a message is queued
+    pub session: Option<Task>,
+    /// tasks waiting for the state changes
+    waiters: Vec<Task>,
 }
 
-impl Client {
-    pub fn connect(addr: &SocketAddr, handle: &Handle) -> ClientConnection {
-        Box::new(TcpClient::new(PeerProto).connect(addr, handle).map(
-            |service| {
-                Client {
-                    inner: Validate { inner: service },
-                    am_choked: true,
-                    am_intrested: false,
-                    peer_choked: true,
-                    peer_intrested: false,
-                    peer_have: HashSet::new(),
-                    peer_requests: HashSet::new(),
-                    blocks: HashMap::new(),
-                    messages: Messages::new(),
-                    info_hash: Vec::new(),
-                }
-            },
-        ))
+impl PeerState {
+    pub fn new(addr: SocketAddr, info_hash: &[u8]) -> Self {
+        PeerState {
+            addr: addr,
+            am_choked: true,
+            am_intrested: false,
+            peer_choked: true,
+            peer_intrested: false,
+            peer_have: HashSet::new(),
+            peer_have_all: false,
+            peer_suggested: HashSet::new(),
+            allowed_fast: HashSet::new(),
+            requests: HashSet::new(),
+            pending: HashSet::new(),
+            peer_requests: VecDeque::new(),
+            window: RequestWindow::new(),
+            request_times: HashMap::new(),
+            blocks: HashMap::new(),
+            storage: None,
+            pieces: None,
+            hashes: HashMap::new(),
+            messages: Messages::new(),
+            info_hash: Vec::from(info_hash),
+            handshaked: false,
+            reserved: reserved(info_hash),
+            peer_reserved: Reserved::empty(),
+            peer_extensions: None,
+            metadata: None,
+            metadata_buffer: None,
+            peer_metadata_requests: VecDeque::new(),
+            outgoing: VecDeque::new(),
+            received: 0,
+            sent: 0,
+            flushed: 0,
+            timeouts: Timeouts::new(),
+            created: Instant::now(),
+            last_received: Instant::now(),
+            last_sent: Instant::now(),
+            slot: None,
+            closed: false,
+            error: None,
+            session: None,
+            waiters: Vec::new(),
+        }
     }
 
-    pub fn handshake(mut self, info_hash: Vec<u8>, id: &[u8]) -> ClientConnection {
-        self.info_hash = info_hash.clone();
-        let msg = Message::Handshake(info_hash, Vec::from(id));
-        Box::new(self.call(msg).and_then(
-            |msgs| self.enqueue(msgs).and(Ok(self)),
-        ))
+    /// received blocks are written to the storage, it may be shared by clients of the torrent
+    pub fn set_storage(&mut self, storage: Rc<RefCell<Storage>>) {
+        self.storage = Some(storage);
+    }
+
+    /// completed pieces are verified and the failed ones are requested again,
+    /// the state is shared by clients of the torrent to find peers sending bad data
+    pub fn set_pieces(&mut self, pieces: Rc<RefCell<Pieces>>) {
+        self.pieces = Some(pieces);
     }
 
-    fn enqueue(&mut self, mut messages: Messages) -> Result<(), io::Error> {
-        self.messages.append(&mut messages);
-        self.dispatch()
+    /// puts the message into the outgoing queue, returns number of the messages
+    /// which shall be flushed to get this one sent
+    pub fn send(&mut self, msg: Message) -> u64 {
+        self.outgoing.push_back(msg);
+        if let Some(ref session) = self.session {
+            session.notify();
+        }
+        self.sent + self.outgoing.len() as u64
     }
 
-    pub fn dispatch(&mut self) -> Result<(), io::Error> {
+    /// marks the connection closed and wakes up everybody waiting for it
+    pub fn close(&mut self, error: Option<PeerError>) {
+        self.closed = true;
+        self.error = error;
+        self.slot = None;
+        self.outgoing.clear();
+        self.notify_waiters();
+    }
+
+    pub fn notify_waiters(&mut self) {
+        for waiter in self.waiters.drain(..) {
+            waiter.notify();
+        }
+    }
+
+    /// processes the received messages
+    pub fn dispatch(&mut self) -> Result<(), PeerError> {
         // println!("client::dispatch() START");
         while let Some(message) = self.messages.pop_front() {
-            if let Some(msg) = message {
-                self.process(msg)?;
+            self.received += 1;
+            self.last_received = Instant::now();
+            match message {
+                Ok(msg) => self.process(msg)?,
+                Err(PeerError::UnknownMessage(id)) => {
+                    // unknown messages shall be ignored
+                    println!("PeerState::dispatch() skip unknown message {}", id);
+                }
+                Err(err) => return Err(err),
             }
         }
         // println!("client::dispatch() END");
+        self.notify_waiters();
         Ok(())
     }
 
-    fn process(&mut self, msg: Message) -> Result<(), io::Error> {
-        println!("Client::process() <= {}", msg);
+    fn process(&mut self, msg: Message) -> Result<(), PeerError> {
+        println!("PeerState::process() <= {}", msg);
         match msg {
-            Message::Handshake(info_hash, _) => {
-                if self.info_hash != info_hash {
-                    return Err(io::Error::new(io::ErrorKind::Other, "Unexpected INFO hash"));
+            Message::Handshake(reserved, info_hash, _) |
+            Message::InfoHash(reserved, info_hash) => {
+                if codec::handshake_hash(&self.info_hash) != &info_hash[..] {
+                    return Err(PeerError::InfoHashMismatch(self.info_hash.clone(), info_hash));
                 }
+                self.peer_reserved = reserved;
+                self.handshaked = true;
+            }
+            Message::PeerId(_) => {
+                // Peer id is not used
             }
 
             Message::KeepAlive() => {
                 // Just a ping
             }
-            Message::Choke() => self.am_choked = true,
+            Message::Choke() => {
+                self.am_choked = true;
+                self.window.choked();
+                if !self.peer_reserved.fast_extension() {
+                    // the peer silently drops all outstanding requests
+                    self.reject_pending();
+                }
+            }
             Message::Unchoke() => self.am_choked = false,
             Message::Interested() => self.peer_intrested = true,
             Message::NotInterested() => self.peer_intrested = false,
@@ -99,25 +243,199 @@ impl Client {
                 self.create_peer_have(bits);
             }
             Message::Request(index, offset, length) => {
-                self.peer_requests.insert((index, offset, length));
+                if self.peer_choked {
+                    // requests of the choked peer are dropped, fast peers are told so
+                    self.reject_request(index, offset, length);
+                } else if !self.can_serve(index, offset, length) {
+                    println!("PeerState::process() can't serve {} {} {}", index, offset, length);
+                    self.reject_request(index, offset, length);
+                } else {
+                    if !self.peer_requests.contains(&(index, offset, length)) {
+                        self.peer_requests.push_back((index, offset, length));
+                    }
+                }
             }
             Message::Piece(index, offset, block) => {
-                self.blocks.insert((index, offset), block);
+                let length = block.len() as u32;
+                if !self.pending.remove(&(index, offset, length)) {
+                    println!("PeerState::process() skip unrequested block {} {}", index, offset);
+                    return Ok(());
+                }
+                self.window.received(length);
+                let verified = self.pieces.as_ref().map_or(false, |pieces| {
+                    pieces.borrow().has(index)
+                });
+                if verified {
+                    // the piece is already on the disk, it's never overwritten
+                    return Ok(());
+                }
+                match self.storage {
+                    Some(ref storage) => storage.borrow_mut().write_block(index, offset, &block)?,
+                    None => {
+                        self.blocks.insert((index, offset), block);
+                    }
+                }
+                self.check_piece(index, offset, length)?;
             }
             Message::Cancel(index, offset, length) => {
-                self.peer_requests.remove(&(index, offset, length));
+                self.peer_requests.retain(|&request| request != (index, offset, length));
+                // the block may be still waiting in the queue
+                self.drop_pieces(|request| request == (index, offset, length));
             }
             Message::Port(_) => {
                 // Not implemented
             }
-            //_ => return Err(io::Error::new(io::ErrorKind::Other, "Unexpected message")),
+            Message::SuggestPiece(_) |
+            Message::HaveAll() |
+            Message::HaveNone() |
+            Message::RejectRequest(_, _, _) |
+            Message::AllowedFast(_) if !self.peer_reserved.fast_extension() => {
+                return Err(PeerError::ProtocolViolation(
+                    "Fast Extension message without Fast Extension support",
+                ));
+            }
+            Message::SuggestPiece(index) => {
+                self.peer_suggested.insert(index);
+            }
+            Message::HaveAll() => {
+                self.peer_have_all = true;
+            }
+            Message::HaveNone() => {
+                self.peer_have_all = false;
+                self.peer_have.clear();
+            }
+            Message::RejectRequest(index, offset, length) => {
+                if self.pending.remove(&(index, offset, length)) {
+                    self.requests.insert((index, offset, length));
+                }
+            }
+            Message::AllowedFast(index) => {
+                self.allowed_fast.insert(index);
+            }
+            Message::Extended(_, _) if !self.peer_reserved.extension_protocol() => {
+                return Err(PeerError::ProtocolViolation(
+                    "Extended message without Extension Protocol support",
+                ));
+            }
+            Message::Extended(extension::HANDSHAKE_ID, payload) => {
+                self.peer_extensions = ExtendedHandshake::decode(&payload);
+            }
+            Message::Extended(metadata::LOCAL_ID, payload) => {
+                match MetadataMessage::decode(&payload) {
+                    Some(msg) => self.process_metadata(msg)?,
+                    None => println!("PeerState::process() skip malformed ut_metadata message"),
+                }
+            }
+            Message::Extended(_, _) => {
+                // Not implemented
+            }
+            Message::HashRequest(_, _, _, _, _) |
+            Message::Hashes(_, _, _, _, _, _) |
+            Message::HashReject(_, _, _, _, _) if !self.peer_reserved.v2() => {
+                return Err(PeerError::ProtocolViolation(
+                    "Hash message without BitTorrent v2 support",
+                ));
+            }
+            Message::HashRequest(_, _, _, _, _) => {
+                // Not implemented
+            }
+            Message::Hashes(root, base, index, _, _, hashes) => {
+                self.hashes.insert((root, base, index), hashes);
+            }
+            Message::HashReject(_, _, _, _, _) => {
+                // Not implemented
+            }
+        }
+        Ok(())
+    }
+
+    fn process_metadata(&mut self, msg: MetadataMessage) -> Result<(), PeerError> {
+        println!("PeerState::process_metadata() <= {:?}", msg);
+        match msg {
+            MetadataMessage::Request(index) => {
+                self.peer_metadata_requests.push_back(index);
+            }
+            MetadataMessage::Data(index, total_size, data) => {
+                let verified = match self.metadata_buffer {
+                    Some(ref mut buffer) => {
+                        if !buffer.insert(index, total_size, data) {
+                            return Err(PeerError::ProtocolViolation(
+                                "Metadata piece doesn't match the metadata size",
+                            ));
+                        }
+                        if !buffer.is_complete() {
+                            return Ok(());
+                        }
+                        buffer.verify()
+                    }
+                    // not requested, e.g. arrived after the metadata is complete
+                    None => return Ok(()),
+                };
+                match verified {
+                    Some(info) => {
+                        self.metadata = Some(info);
+                        self.metadata_buffer = None;
+                    }
+                    None => {
+                        return Err(PeerError::ProtocolViolation(
+                            "Metadata doesn't match the info hash",
+                        ))
+                    }
+                }
+            }
+            MetadataMessage::Reject(index) => {
+                if let Some(ref mut buffer) = self.metadata_buffer {
+                    buffer.reject(index);
+                }
+            }
         }
         Ok(())
     }
 
-    fn create_peer_have(&mut self, bits: Vec<u8>) {
+    /// verifies the piece once all its blocks are received,
+    /// blocks of the piece which doesn't match its hash go back into the request pool
+    fn check_piece(&mut self, index: u32, offset: u32, length: u32) -> Result<(), PeerError> {
+        let pieces = match self.pieces {
+            Some(ref pieces) => pieces.clone(),
+            None => return Ok(()),
+        };
+        let mut pieces = pieces.borrow_mut();
+        if !pieces.received(index, offset, length, self.addr) {
+            return Ok(());
+        }
+        let valid = match self.storage {
+            Some(ref storage) => storage.borrow_mut().verify_piece(index)?,
+            None => {
+                let mut piece = Vec::new();
+                for &(_, offset, _) in &pieces.blocks(index) {
+                    if let Some(block) = self.blocks.get(&(index, offset)) {
+                        piece.extend_from_slice(block);
+                    }
+                }
+                pieces.layout().piece_hash(index) == Some(&sha1(&piece))
+            }
+        };
+        if valid {
+            println!("PeerState::check_piece() piece {} is OK", index);
+            pieces.verified(index);
+        } else {
+            println!("PeerState::check_piece() piece {} is BAD", index);
+            for request in pieces.failed(index) {
+                self.blocks.remove(&(request.0, request.1));
+                self.requests.insert(request);
+            }
+        }
+        Ok(())
+    }
+
+    /// returns true if the peer has sent too many pieces which failed the hash check
+    pub fn is_banned(&self) -> bool {
+        self.pieces.as_ref().map_or(false, |pieces| pieces.borrow().is_banned(&self.addr))
+    }
+
+    fn create_peer_have(&mut self, bits: Bytes) {
         let mut index = 0;
-        for byte in &bits {
+        for byte in bits.as_ref() {
             if 0 != *byte & 0b1000_0000u8 {
                 self.peer_have(index + 0);
             }
@@ -150,61 +468,590 @@ impl Client {
         self.peer_have.insert(index);
     }
 
-    pub fn unchoke_me(mut self) -> ClientConnection {
-        Box::new(self.call(Message::Interested()).and_then(|msgs| {
-            self.enqueue(msgs).and(Ok(self))
-        }))
+    /// returns true if the peer has announced the piece
+    pub fn peer_has(&self, index: u32) -> bool {
+        self.peer_have_all || self.peer_have.contains(&index)
     }
 
-    pub fn choke_me(mut self) -> ClientConnection {
-        Box::new(self.call(Message::NotInterested()).and_then(|msgs| {
-            self.enqueue(msgs).and(Ok(self))
-        }))
+    /// returns true if the piece may be requested right now
+    pub fn can_request(&self, index: u32) -> bool {
+        self.peer_has(index) && (!self.am_choked || self.allowed_fast.contains(&index))
+    }
+
+    /// returns true if there is neither queued nor outstanding request
+    pub fn is_done(&self) -> bool {
+        self.requests.is_empty() && self.pending.is_empty()
+    }
+
+    /// put request into the request pool
+    pub fn enqueue_request(&mut self, request: (u32, u32, u32)) {
+        self.requests.insert(request);
+    }
+
+    /// returns next request from the pool which may be sent right now,
+    /// pieces suggested by the peer go first
+    pub fn next_request(&self) -> Option<(u32, u32, u32)> {
+        let mut allowed = self.requests.iter().filter(
+            |&&(index, _, _)| self.can_request(index),
+        );
+        let suggested = allowed.clone().find(|&&(index, _, _)| {
+            self.peer_suggested.contains(&index)
+        });
+        suggested.or_else(|| allowed.next()).cloned()
+    }
+
+    /// sends requests from the pool until the window is full, returns number of the sent ones
+    pub fn fill_requests(&mut self) -> usize {
+        if !self.handshaked {
+            return 0;
+        }
+        if self.pending.is_empty() {
+            self.window.resume();
+        } else if self.window.check_stall() {
+            println!("PeerState::fill_requests() peer {} is slow, window shrinks", self.addr);
+        }
+        let reqq = self.peer_extensions.as_ref().and_then(|ext| ext.reqq);
+        let size = self.window.size(reqq) as usize;
+        let mut sent = 0;
+        while self.pending.len() < size {
+            let request = match self.next_request() {
+                Some(request) => request,
+                None => break,
+            };
+            self.requested(request);
+            self.outgoing.push_back(Message::Request(request.0, request.1, request.2));
+            sent += 1;
+        }
+        sent
+    }
+
+    /// moves the request from the pool to the outstanding ones
+    fn requested(&mut self, request: (u32, u32, u32)) {
+        self.requests.remove(&request);
+        self.pending.insert(request);
+        self.request_times.insert(request, Instant::now());
+    }
+
+    /// returns true if the block is in the verified piece and isn't too long
+    fn can_serve(&self, index: u32, offset: u32, length: u32) -> bool {
+        let pieces = match (&self.pieces, &self.storage) {
+            (&Some(ref pieces), &Some(_)) => pieces.borrow(),
+            _ => return false,
+        };
+        0 < length && length <= BLOCK_LEN && pieces.has(index) &&
+            pieces.layout().slices(index, offset, length).is_some()
     }
 
+    /// tells the peer that its request won't be served, if it supports the Fast Extension
+    fn reject_request(&mut self, index: u32, offset: u32, length: u32) {
+        if self.peer_reserved.fast_extension() {
+            self.outgoing.push_back(Message::RejectRequest(index, offset, length));
+        }
+    }
+
+    /// reads the requested blocks from the storage and queues them, only a few blocks are
+    /// queued at once, so the peer may cancel the rest; returns true if requests remain
+    pub fn serve_requests(&mut self) -> bool {
+        while !self.peer_choked && self.outgoing.len() < UPLOAD_QUEUE {
+            let (index, offset, length) = match self.peer_requests.pop_front() {
+                Some(request) => request,
+                None => break,
+            };
+            let block = match self.storage {
+                Some(ref storage) => storage.borrow_mut().read_block(index, offset, length),
+                None => break,
+            };
+            match block {
+                Ok(block) => {
+                    if let Some(ref pieces) = self.pieces {
+                        pieces.borrow_mut().uploaded += length as u64;
+                    }
+                    self.outgoing.push_back(Message::Piece(index, offset, Bytes::from(block)));
+                }
+                Err(err) => {
+                    println!("PeerState::serve_requests() can't read the block: {}", err);
+                    self.reject_request(index, offset, length);
+                }
+            }
+        }
+        !self.peer_requests.is_empty()
+    }
+
+    /// chokes the peer, its pending requests are dropped
+    pub fn choke_peer(&mut self) {
+        self.peer_choked = true;
+        let requests = self.peer_requests.drain(..).collect::<Vec<_>>();
+        for (index, offset, length) in requests {
+            self.reject_request(index, offset, length);
+        }
+        self.drop_pieces(|_| true);
+    }
+
+    /// removes the matching blocks from the outgoing queue
+    fn drop_pieces<F: Fn((u32, u32, u32)) -> bool>(&mut self, matches: F) {
+        let queued = self.outgoing.len();
+        self.outgoing.retain(|msg| match msg {
+            &Message::Piece(index, offset, ref block) => {
+                !matches((index, offset, block.len() as u32))
+            }
+            _ => true,
+        });
+        // dropped messages are counted as sent, so senders of the later ones aren't stuck
+        self.sent += (queued - self.outgoing.len()) as u64;
+    }
+
+    /// returns the message which announces our pieces, nothing if we have none
+    /// and the peer doesn't support HaveNone
+    pub fn have_message(&self) -> Option<Message> {
+        let fast = self.peer_reserved.fast_extension();
+        let pieces = match self.pieces {
+            Some(ref pieces) => pieces.borrow(),
+            None if fast => return Some(Message::HaveNone()),
+            None => return None,
+        };
+        if 0 == pieces.have_count() {
+            return if fast { Some(Message::HaveNone()) } else { None };
+        }
+        if fast && pieces.is_complete() {
+            return Some(Message::HaveAll());
+        }
+        Some(Message::Bitfield(Bytes::from(pieces.bitfield())))
+    }
+
+    /// fires the connection timers, fails if the peer has to be dropped
+    pub fn check_timers(&mut self) -> Result<(), PeerError> {
+        let now = Instant::now();
+        if !self.handshaked && now.duration_since(self.created) >= self.timeouts.handshake {
+            return Err(PeerError::Timeout("peer hasn't answered the handshake"));
+        }
+        if now.duration_since(self.last_received) >= self.timeouts.idle {
+            return Err(PeerError::Timeout("peer is silent for too long"));
+        }
+        let pending = &self.pending;
+        self.request_times.retain(|request, _| pending.contains(request));
+        let timeout = self.timeouts.request;
+        let expired = self.request_times
+            .iter()
+            .filter(|&(_, &time)| now.duration_since(time) >= timeout)
+            .map(|(&request, _)| request)
+            .collect::<Vec<_>>();
+        for request in &expired {
+            println!("PeerState::check_timers() request {:?} has expired", request);
+            self.request_times.remove(request);
+            self.pending.remove(request);
+            self.requests.insert(*request);
+            // the late block would arrive as unrequested otherwise
+            let &(index, offset, length) = request;
+            self.outgoing.push_back(Message::Cancel(index, offset, length));
+        }
+        if !expired.is_empty() {
+            self.window.expired();
+            self.notify_waiters();
+        }
+        if self.outgoing.is_empty() &&
+            now.duration_since(self.last_sent) >= self.timeouts.keep_alive
+        {
+            self.outgoing.push_back(Message::KeepAlive());
+        }
+        Ok(())
+    }
 
-    pub fn unchoke_peer(mut self) -> ClientConnection {
-        Box::new(self.call(Message::Unchoke()).and_then(|msgs| {
-            self.enqueue(msgs).and(Ok(self))
+    /// move all outstanding requests back into the request pool
+    fn reject_pending(&mut self) {
+        for request in self.pending.drain() {
+            self.requests.insert(request);
+        }
+    }
+
+    /// returns true if the peer can send us the metadata
+    pub fn peer_has_metadata(&self) -> bool {
+        self.peer_extensions.as_ref().map_or(false, |ext| {
+            ext.id(metadata::EXTENSION_NAME).is_some() && ext.metadata_size.is_some()
+        })
+    }
+
+    /// returns the request of the next missing metadata piece
+    fn metadata_request(&mut self) -> Option<Message> {
+        if self.metadata.is_some() || !self.peer_has_metadata() {
+            return None;
+        }
+        let (id, size) = {
+            let ext = self.peer_extensions.as_ref().unwrap();
+            (ext.id(metadata::EXTENSION_NAME).unwrap(), ext.metadata_size.unwrap())
+        };
+        if self.metadata_buffer.as_ref().map_or(true, |buffer| buffer.size() != size as usize) {
+            self.metadata_buffer = MetadataBuffer::new(&self.info_hash, size as usize);
+        }
+        self.metadata_buffer.as_mut().and_then(|buffer| buffer.next_request()).map(
+            |index| {
+                Message::Extended(id, MetadataMessage::Request(index).encode())
+            },
+        )
+    }
+
+    /// returns the answer to the oldest metadata request of the peer
+    fn metadata_response(&mut self) -> Option<Message> {
+        let id = self.peer_extensions.as_ref().and_then(|ext| {
+            ext.id(metadata::EXTENSION_NAME)
+        });
+        let (id, index) = match (id, self.peer_metadata_requests.pop_front()) {
+            (Some(id), Some(index)) => (id, index),
+            _ => return None,
+        };
+        let response = match self.metadata {
+            Some(ref info) => {
+                match metadata::piece(info, index) {
+                    Some(piece) => {
+                        MetadataMessage::Data(index, info.len() as u32, Vec::from(piece))
+                    }
+                    None => MetadataMessage::Reject(index),
+                }
+            }
+            None => MetadataMessage::Reject(index),
+        };
+        Some(Message::Extended(id, response.encode()))
+    }
+}
+
+/// returns true if the peer has closed the connection, e.g. it doesn't expect MSE
+fn is_dropped(err: &PeerError) -> bool {
+    match err {
+        &PeerError::Io(ref err) => {
+            match err.kind() {
+                io::ErrorKind::UnexpectedEof |
+                io::ErrorKind::ConnectionReset |
+                io::ErrorKind::ConnectionAborted |
+                io::ErrorKind::BrokenPipe => true,
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// returns our reserved bits, v2 is announced if the info hash is SHA-256
+fn reserved(info_hash: &[u8]) -> Reserved {
+    let mut reserved = Reserved::new();
+    reserved.set_v2(SHA256_LEN == info_hash.len());
+    reserved
+}
+
+/// Handle of the connection to the peer, the connection is closed when it's dropped
+pub struct Client {
+    state: Rc<RefCell<PeerState>>,
+    /// number of the processed messages seen by update()
+    seen: u64,
+}
+
+impl Client {
+    /// connects to the peer, info hash is needed by the stream encryption handshake
+    pub fn connect(
+        addr: &SocketAddr,
+        handle: &Handle,
+        info_hash: &[u8],
+        encryption: Encryption,
+        transport: Transport,
+    ) -> ClientConnection {
+        let addr = *addr;
+        let handle = handle.clone();
+        let state = PeerState::new(addr, info_hash);
+        let info_hash = Vec::from(info_hash);
+        let stream = transport::connect(&addr, &handle, &transport).map_err(PeerError::from);
+        let stream: Box<Future<Item = MseStream<PeerStream>, Error = PeerError>> = match encryption {
+            Encryption::Disabled => Box::new(stream.map(MseStream::plain)),
+            Encryption::Forced => {
+                Box::new(stream.and_then(move |stream| {
+                    mse::connect(stream, &info_hash, encryption)
+                }))
+            }
+            Encryption::Enabled => {
+                // peers without MSE support drop the connection, so retry it in plaintext;
+                // failed connects and MSE protocol errors are final
+                let fallback = handle.clone();
+                Box::new(stream.and_then(move |stream| {
+                    mse::connect(stream, &info_hash, encryption).or_else(move |err| {
+                        if !is_dropped(&err) {
+                            return Box::new(future::err(err)) as MseConnection<PeerStream>;
+                        }
+                        Box::new(
+                            transport::connect(&addr, &fallback, &transport)
+                                .map(MseStream::plain)
+                                .map_err(PeerError::from),
+                        )
+                    })
+                }))
+            }
+        };
+        Box::new(stream.and_then(move |stream| {
+            Client::spawn(&handle, stream.framed(PeerCodec::new()), state).map_err(PeerError::from)
         }))
     }
 
-    pub fn request(mut self, index: u32, offset: u32, size: u32) -> ClientConnection {
-        Box::new(self.call(Message::Request(index, offset, size)).and_then(
-            |msgs| {
-                self.enqueue(msgs).and(Ok(self))
+    /// runs the session of the connection in the background, the transport may have
+    /// some messages already decoded, e.g. the handshake of the incoming connection
+    pub fn spawn<T>(
+        handle: &Handle,
+        transport: Framed<T, PeerCodec>,
+        mut state: PeerState,
+    ) -> io::Result<Client>
+    where
+        T: AsyncRead + AsyncWrite + 'static,
+    {
+        // timers start with the session
+        let now = Instant::now();
+        state.created = now;
+        state.last_received = now;
+        state.last_sent = now;
+        let state = Rc::new(RefCell::new(state));
+        handle.spawn(PeerSession::new(transport, state.clone(), handle)?);
+        Ok(Client::new(state))
+    }
+
+    /// creates the handle of the state which is driven by the already spawned session
+    pub fn new(state: Rc<RefCell<PeerState>>) -> Client {
+        Client {
+            state: state,
+            seen: 0,
+        }
+    }
+
+    /// returns the current state of the peer
+    pub fn state(&self) -> Ref<PeerState> {
+        self.state.borrow()
+    }
+
+    pub fn state_mut(&self) -> RefMut<PeerState> {
+        self.state.borrow_mut()
+    }
+
+    /// received blocks are written to the storage, it may be shared by clients of the torrent
+    pub fn set_storage(&mut self, storage: Rc<RefCell<Storage>>) {
+        self.state.borrow_mut().set_storage(storage);
+    }
+
+    /// completed pieces are verified and the failed ones are requested again,
+    /// the state is shared by clients of the torrent to find peers sending bad data
+    pub fn set_pieces(&mut self, pieces: Rc<RefCell<Pieces>>) {
+        self.state.borrow_mut().set_pieces(pieces);
+    }
+
+    /// sets bounds of the number of the requests in flight
+    pub fn set_request_window(&mut self, min: u32, max: u32) {
+        self.state.borrow_mut().window.set_limits(min, max);
+    }
+
+    /// sets durations of the keep-alive, handshake, request and idle timers
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.state.borrow_mut().timeouts = timeouts;
+    }
+
+    /// announces v2 support in the handshake, it's set by default only for SHA-256 info hashes,
+    /// so hybrid torrents which are joined by their v1 info hash shall enable it
+    pub fn set_v2(&mut self, enabled: bool) {
+        self.state.borrow_mut().reserved.set_v2(enabled);
+    }
+
+    /// sends our handshake, resolves when the handshake of the peer is received
+    pub fn handshake(self, info_hash: Vec<u8>, id: &[u8]) -> ClientConnection {
+        let target = {
+            let mut state = self.state.borrow_mut();
+            if SHA256_LEN == info_hash.len() {
+                state.reserved.set_v2(true);
+            }
+            state.info_hash = info_hash.clone();
+            let msg = Message::Handshake(state.reserved, info_hash, Vec::from(id));
+            state.send(msg)
+        };
+        self.until(move |state| target <= state.flushed && state.handshaked)
+    }
+
+    /// queues the message, resolves when it's sent
+    pub fn send(self, msg: Message) -> ClientConnection {
+        if let Err(err) = codec::validate(&msg) {
+            return Box::new(future::err(err));
+        }
+        let target = self.state.borrow_mut().send(msg);
+        self.until(move |state| target <= state.flushed)
+    }
+
+    /// resolves when a message is processed since the previous update
+    pub fn update(self) -> ClientConnection {
+        let seen = self.seen;
+        Box::new(self.until(move |state| seen < state.received).map(
+            |mut client| {
+                client.seen = client.state.borrow().received;
+                client
             },
         ))
     }
 
+    /// resolves when the condition is met, fails if the connection is closed before
+    fn until<F>(self, condition: F) -> ClientConnection
+    where
+        F: Fn(&PeerState) -> bool + 'static,
+    {
+        Box::new(Until {
+            client: Some(self),
+            condition: condition,
+        })
+    }
+
+    /// returns true if there is neither queued nor outstanding request
+    pub fn is_done(&self) -> bool {
+        self.state.borrow().is_done()
+    }
+
+    /// put request into the request pool, the session sends it when the window allows
+    pub fn enqueue_request(&mut self, request: (u32, u32, u32)) {
+        let mut state = self.state.borrow_mut();
+        state.enqueue_request(request);
+        if let Some(ref session) = state.session {
+            session.notify();
+        }
+    }
+
+    /// returns next request from the pool which may be sent right now
+    pub fn next_request(&self) -> Option<(u32, u32, u32)> {
+        self.state.borrow().next_request()
+    }
+
+    /// returns true if the peer has sent too many pieces which failed the hash check
+    pub fn is_banned(&self) -> bool {
+        self.state.borrow().is_banned()
+    }
+
+    /// returns true if the peer can send us the metadata
+    pub fn peer_has_metadata(&self) -> bool {
+        self.state.borrow().peer_has_metadata()
+    }
+
+    pub fn unchoke_me(self) -> ClientConnection {
+        self.send(Message::Interested())
+    }
+
+    pub fn choke_me(self) -> ClientConnection {
+        self.send(Message::NotInterested())
+    }
+
+    pub fn unchoke_peer(self) -> ClientConnection {
+        self.state.borrow_mut().peer_choked = false;
+        self.send(Message::Unchoke())
+    }
+
+    /// stops serving the peer, its not yet sent blocks are dropped
+    pub fn choke_peer(self) -> ClientConnection {
+        self.state.borrow_mut().choke_peer();
+        self.send(Message::Choke())
+    }
+
+    pub fn request(self, index: u32, offset: u32, size: u32) -> ClientConnection {
+        self.send(Message::Request(index, offset, size))
+    }
+
+    /// requests length hashes of the layer base of the file tree with pieces root
+    pub fn hash_request(
+        self,
+        root: &[u8],
+        base: u32,
+        index: u32,
+        length: u32,
+        proof: u32,
+    ) -> ClientConnection {
+        self.send(Message::HashRequest(Vec::from(root), base, index, length, proof))
+    }
+
     pub fn download(self, request: &(u32, u32, u32)) -> ClientConnection {
+        self.state.borrow_mut().requested(*request);
         self.request(request.0, request.1, request.2)
     }
 
-    pub fn bitfield(mut self) -> ClientConnection {
-        Box::new(self.call(Message::Bitfield(Vec::new())).and_then(|msgs| {
-            self.enqueue(msgs).and(Ok(self))
-        }))
+    /// announces the verified pieces, HaveAll or HaveNone if the peer supports them
+    pub fn bitfield(self) -> ClientConnection {
+        let msg = self.state.borrow().have_message();
+        match msg {
+            Some(msg) => self.send(msg),
+            None => Box::new(future::ok(self)),
+        }
     }
 
-    pub fn ping(mut self) -> ClientConnection {
-        Box::new(self.call(Message::KeepAlive()).and_then(|msgs| {
-            self.enqueue(msgs).and(Ok(self))
-        }))
+    /// sends the extension handshake if the peer has announced extension protocol support,
+    /// ut_metadata is always announced as the client handles it by itself
+    pub fn extended_handshake(self, handshake: &ExtendedHandshake) -> ClientConnection {
+        if !self.state.borrow().peer_reserved.extension_protocol() {
+            return Box::new(future::ok(self));
+        }
+        let msg = {
+            let state = self.state.borrow();
+            let mut handshake = handshake.clone();
+            handshake.m.insert(metadata::EXTENSION_NAME.to_string(), metadata::LOCAL_ID);
+            if let Some(ref info) = state.metadata {
+                handshake.metadata_size = Some(info.len() as u32);
+            }
+            Message::Extended(extension::HANDSHAKE_ID, handshake.encode())
+        };
+        self.send(msg)
+    }
+
+    /// requests the next missing metadata piece, does nothing if the metadata is known,
+    /// the peer doesn't support ut_metadata or all pieces are already requested
+    pub fn request_metadata(self) -> ClientConnection {
+        let msg = self.state.borrow_mut().metadata_request();
+        match msg {
+            Some(msg) => self.send(msg),
+            None => Box::new(future::ok(self)),
+        }
+    }
+
+    /// answers the oldest metadata request of the peer
+    pub fn serve_metadata(self) -> ClientConnection {
+        let msg = self.state.borrow_mut().metadata_response();
+        match msg {
+            Some(msg) => self.send(msg),
+            None => Box::new(future::ok(self)),
+        }
+    }
+
+    pub fn ping(self) -> ClientConnection {
+        self.send(Message::KeepAlive())
     }
 }
 
-impl Service for Client {
-    type Request = Message;
-    type Response = Messages;
-    type Error = io::Error;
-    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
-
-    fn call(&self, request: Self::Request) -> Self::Future {
-        println!("Client: Request to Server: {:?}", request);
-        Box::new(self.inner.call(request).and_then(|response| {
-            //println!("Client: Response from Server: {:?}", response);
-            Ok(response)
-        }))
+impl Drop for Client {
+    fn drop(&mut self) {
+        // the session notices that it's the only owner of the state and closes the connection
+        if let Some(ref session) = self.state.borrow().session {
+            session.notify();
+        }
+    }
+}
+
+/// Resolves to the client once the condition on its state is met
+struct Until<F> {
+    client: Option<Client>,
+    condition: F,
+}
+
+impl<F: Fn(&PeerState) -> bool> Future for Until<F> {
+    type Item = Client;
+    type Error = PeerError;
+
+    fn poll(&mut self) -> Poll<Client, PeerError> {
+        {
+            let client = self.client.as_ref().expect("Until is polled after completion");
+            let mut state = client.state.borrow_mut();
+            if !(self.condition)(&state) {
+                if state.closed {
+                    return Err(state.error.take().unwrap_or_else(|| {
+                        PeerError::Io(io::Error::new(
+                            io::ErrorKind::ConnectionAborted,
+                            "connection to the peer is closed",
+                        ))
+                    }));
+                }
+                state.waiters.push(task::current());
+                return Ok(Async::NotReady);
+            }
+        }
+        Ok(Async::Ready(self.client.take().unwrap()))
     }
 }
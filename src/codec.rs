@@ -1,24 +1,29 @@
 use std::io;
 use std::str;
+use std::cmp;
 use std::mem::size_of;
 use rustc_serialize::hex::ToHex;
 
-use std::thread;
-use std::time::Duration;
-
 use bytes::BytesMut;
 use tokio_io::codec::{Encoder, Decoder};
 use byteorder::{ByteOrder, BigEndian};
 
 use Message;
 use Messages;
+use Reserved;
+use PeerError;
+use reserved::RESERVED_LEN;
 
 const PSTR: &'static str = "BitTorrent protocol";
 const PSTR_SIZE: usize = 19;
 const HASH_INFO_LEN: usize = 20;
+/// SHA-256 info hash of v2 torrents, it is truncated to 20 bytes in the handshake
+const HASH_INFO_V2_LEN: usize = 32;
+const PIECES_ROOT_LEN: usize = 32;
+const HASH_LEN: usize = 32;
 const PEER_ID_LEN: usize = 20;
-const RESERVED_LEN: usize = 8;
-const RESERVED: [u8; RESERVED_LEN] = [0, 0, 0, 0, 0, 0, 0, 0];
+/// <len><id=7><index><begin> of the piece message
+const PIECE_HEADER_LEN: usize = 13;
 
 const BYTE_SIZE: usize = size_of::<u8>();
 const SHORT_SIZE: usize = size_of::<u16>();
@@ -34,120 +39,295 @@ const REQUEST_ID: u8 = 6;
 const PIECE_ID: u8 = 7;
 const CANCEL_ID: u8 = 8;
 const PORT_ID: u8 = 9;
+const SUGGEST_PIECE_ID: u8 = 13;
+const HAVE_ALL_ID: u8 = 14;
+const HAVE_NONE_ID: u8 = 15;
+const REJECT_REQUEST_ID: u8 = 16;
+const ALLOWED_FAST_ID: u8 = 17;
+const EXTENDED_ID: u8 = 20;
+const HASH_REQUEST_ID: u8 = 21;
+const HASHES_ID: u8 = 22;
+const HASH_REJECT_ID: u8 = 23;
+
+/// Default limit of the payload length, enough for a 128 KiB block or a bitfield of 1M pieces
+pub const MAX_FRAME_SIZE: usize = 0x20000 + 0x100;
 
+/// Decoder state of the connection
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum State {
+    /// nothing is received yet, the handshake is expected
+    Handshake,
+    /// info hash is received, the peer id is expected (split handshake only)
+    PeerId,
+    /// handshake is done, only length prefixed messages are expected
+    Frames,
+}
 
-pub struct PeerCodec;
+pub struct PeerCodec {
+    max_frame_size: usize,
+    split_handshake: bool,
+    state: State,
+}
 impl PeerCodec {
-    fn handshake(&self, buf: &mut BytesMut) -> Option<Message> {
+    pub fn new() -> Self {
+        Self::with_max_frame_size(MAX_FRAME_SIZE)
+    }
+
+    /// returns codec which rejects frames with payload longer than max_frame_size bytes
+    pub fn with_max_frame_size(max_frame_size: usize) -> Self {
+        PeerCodec {
+            max_frame_size: max_frame_size,
+            split_handshake: false,
+            state: State::Handshake,
+        }
+    }
+
+    /// if enabled the handshake is decoded in two stages: Message::InfoHash as soon as
+    /// the info hash is received and Message::PeerId later
+    pub fn set_split_handshake(&mut self, enabled: bool) {
+        self.split_handshake = enabled;
+    }
+
+    fn protocol(&self, buf: &BytesMut) -> Result<(), PeerError> {
         //<PSTRLIN: u8><PSTR: 'BitTorrent protocol'>
-        //  <RESERVED[0u8; 8]>
+        if !buf.is_empty() && buf[0] as usize != PSTR_SIZE {
+            return Err(PeerError::BadHandshake("Unexpected protocol string length"));
+        }
+        let len = cmp::min(buf.len(), BYTE_SIZE + PSTR_SIZE);
+        if len > BYTE_SIZE && &buf[BYTE_SIZE..len] != &PSTR.as_bytes()[..len - BYTE_SIZE] {
+            return Err(PeerError::BadHandshake("Unexpected protocol string"));
+        }
+        Ok(())
+    }
+
+    fn info_hash(&self, buf: &mut BytesMut) -> Result<Option<Message>, PeerError> {
+        //<PSTRLIN: u8><PSTR: 'BitTorrent protocol'>
+        //  <reserved: [u8; 8]>
         //  <info_hash: [u8; 20]>
-        //  <peer_id: [u8; 20]>
-        const HANDSHAKE_LENGTH: usize = BYTE_SIZE + PSTR_SIZE + RESERVED_LEN + HASH_INFO_LEN +
-            PEER_ID_LEN;
+        const INFO_HASH_LENGTH: usize = BYTE_SIZE + PSTR_SIZE + RESERVED_LEN + HASH_INFO_LEN;
 
-        if HANDSHAKE_LENGTH <= buf.len() && buf[0] as usize == PSTR_SIZE &&
-            &buf[1..(PSTR_SIZE + BYTE_SIZE) as usize] == PSTR.as_bytes()
-        {
+        self.protocol(buf)?;
+        if INFO_HASH_LENGTH <= buf.len() {
             let mut hash_info = Vec::with_capacity(HASH_INFO_LEN);
-            let mut peer_id = Vec::with_capacity(PEER_ID_LEN);
             buf.split_to(BYTE_SIZE); // consume PSTR_SIZE
             buf.split_to(PSTR_SIZE); // consume PSTR
-            buf.split_to(RESERVED_LEN); // consume RESERVED
+            let reserved = Reserved::from_bytes(&buf.split_to(RESERVED_LEN));
             hash_info.extend_from_slice(buf.split_to(HASH_INFO_LEN).as_ref());
+            Ok(Some(Message::InfoHash(reserved, hash_info)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn peer_id(&self, buf: &mut BytesMut) -> Option<Message> {
+        //  <peer_id: [u8; 20]>
+        if PEER_ID_LEN <= buf.len() {
+            let mut peer_id = Vec::with_capacity(PEER_ID_LEN);
             peer_id.extend_from_slice(buf.split_to(PEER_ID_LEN).as_ref());
-            Some(Message::Handshake(hash_info, peer_id))
+            Some(Message::PeerId(peer_id))
         } else {
             None
         }
     }
 
-    fn choke(&self) -> Option<Message> {
+    fn handshake(&self, buf: &mut BytesMut) -> Result<Option<Message>, PeerError> {
+        //<PSTRLIN: u8><PSTR: 'BitTorrent protocol'>
+        //  <reserved: [u8; 8]>
+        //  <info_hash: [u8; 20]>
+        //  <peer_id: [u8; 20]>
+        const HANDSHAKE_LENGTH: usize = BYTE_SIZE + PSTR_SIZE + RESERVED_LEN + HASH_INFO_LEN +
+            PEER_ID_LEN;
+
+        self.protocol(buf)?;
+        if HANDSHAKE_LENGTH <= buf.len() {
+            match (self.info_hash(buf)?, self.peer_id(buf)) {
+                (Some(Message::InfoHash(reserved, hash_info)), Some(Message::PeerId(peer_id))) => {
+                    Ok(Some(Message::Handshake(reserved, hash_info, peer_id)))
+                }
+                _ => unreachable!(),
+            }
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn frames(&self, buf: &mut BytesMut, messages: &mut Messages) -> Result<(), PeerError> {
+        while buf.len() >= size_of::<u32>() {
+            let payload_length = BigEndian::read_u32(&buf) as usize;
+            if payload_length > self.max_frame_size {
+                return Err(PeerError::OversizedFrame(payload_length, self.max_frame_size));
+            }
+            let message_length = size_of::<u32>() + payload_length;
+            if buf.len() < message_length {
+                break;
+            }
+            buf.split_to(size_of::<u32>()); // consume payload length
+
+            if 0 == payload_length {
+                messages.push_back(Ok(Message::KeepAlive()));
+                continue;
+            }
+            let msg_code = buf.split_to(1)[0]; // consume cmd code
+            check_length(msg_code, payload_length)?;
+            let msg = match msg_code {
+                CHOCKE_ID => self.choke(),
+                UNCHOCKE_ID => self.unchoke(),
+                INTERESTED_ID => self.interested(),
+                NOT_INTERESTED_ID => self.not_interested(),
+                HAVE_ID => self.have(buf),
+                BITFIELD_ID => self.bitfield(buf, payload_length),
+                REQUEST_ID => self.request(buf),
+                PIECE_ID => self.piece(buf, payload_length),
+                CANCEL_ID => self.cancel(buf),
+                PORT_ID => self.port(buf),
+                SUGGEST_PIECE_ID => self.suggest_piece(buf),
+                HAVE_ALL_ID => self.have_all(),
+                HAVE_NONE_ID => self.have_none(),
+                REJECT_REQUEST_ID => self.reject_request(buf),
+                ALLOWED_FAST_ID => self.allowed_fast(buf),
+                EXTENDED_ID => self.extended(buf, payload_length),
+                HASH_REQUEST_ID => self.hash_request(buf),
+                HASHES_ID => self.hashes(buf, payload_length),
+                HASH_REJECT_ID => self.hash_reject(buf),
+                _ => {
+                    println!("Decoder::decode(): Unknown Message: {:X}", msg_code);
+                    buf.split_to(payload_length - BYTE_SIZE); // skip payload
+                    messages.push_back(Err(PeerError::UnknownMessage(msg_code)));
+                    continue;
+                }
+            };
+            messages.push_back(Ok(msg));
+        }
+        Ok(())
+    }
+
+    fn choke(&self) -> Message {
         // choke: <len=0001><id=0>";
-        Some(Message::Choke())
+        Message::Choke()
     }
 
-    fn unchoke(&self) -> Option<Message> {
+    fn unchoke(&self) -> Message {
         // unchoke: <len=0001><id=1>";
-        Some(Message::Unchoke())
+        Message::Unchoke()
     }
 
-    fn interested(&self) -> Option<Message> {
+    fn interested(&self) -> Message {
         // interested: <len=0001><id=2>";
-        Some(Message::Unchoke())
+        Message::Interested()
     }
 
-    fn not_interested(&self) -> Option<Message> {
+    fn not_interested(&self) -> Message {
         // not interested: <len=0001><id=3>";
-        Some(Message::Unchoke())
+        Message::NotInterested()
     }
 
-    fn have(&self, buf: &mut BytesMut) -> Option<Message> {
+    fn have(&self, buf: &mut BytesMut) -> Message {
         // have: <len=0005><id=4><index>
-        if buf.len() >= NUMBER_SIZE {
-            let index = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
-            println!("Msg Have index {}", index);
-            Some(Message::Have(index))
-        } else {
-            None
-        }
+        let index = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
+        Message::Have(index)
     }
 
-    fn bitfield(&self, buf: &mut BytesMut, len: usize) -> Option<Message> {
+    fn bitfield(&self, buf: &mut BytesMut, len: usize) -> Message {
         // bitfield: <len=0001+size_of bitfield><id=5><bitfield>
-        let data_len = len - BYTE_SIZE;
-        if buf.len() >= data_len {
-            let bits = Vec::from(buf.split_to(data_len).as_ref());
-            Some(Message::Bitfield(bits))
-        } else {
-            None
-        }
+        let bits = buf.split_to(len - BYTE_SIZE).freeze();
+        Message::Bitfield(bits)
     }
 
-    fn request(&self, buf: &mut BytesMut) -> Option<Message> {
+    fn request(&self, buf: &mut BytesMut) -> Message {
         // request: <len=0013><id=6><index><begin><length>
-        if buf.len() >= 3 * NUMBER_SIZE {
-            let index = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
-            let begin = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
-            let length = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
-            Some(Message::Request(index, begin, length))
-        } else {
-            None
-        }
+        let index = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
+        let begin = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
+        let length = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
+        Message::Request(index, begin, length)
     }
 
-    fn cancel(&self, buf: &mut BytesMut) -> Option<Message> {
+    fn cancel(&self, buf: &mut BytesMut) -> Message {
         // cancel: <len=0013><id=8><index><begin><length>
-        if buf.len() >= 3 * NUMBER_SIZE {
-            let index = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
-            let begin = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
-            let length = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
-            Some(Message::Cancel(index, begin, length))
-        } else {
-            None
-        }
+        let index = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
+        let begin = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
+        let length = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
+        Message::Cancel(index, begin, length)
     }
 
-    fn piece(&self, buf: &mut BytesMut, len: usize) -> Option<Message> {
-        // piece: <len=0007+X><id=7><index><begin><block>
-        if buf.len() >= len - BYTE_SIZE {
-            let index = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
-            let begin = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
-            let block = Vec::from(buf.split_to(len - BYTE_SIZE - 2 * NUMBER_SIZE).as_ref());
-            Some(Message::Piece(index, begin, block))
-        } else {
-            None
-        }
+    fn piece(&self, buf: &mut BytesMut, len: usize) -> Message {
+        // piece: <len=0009+X><id=7><index><begin><block>
+        let index = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
+        let begin = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
+        let block = buf.split_to(len - BYTE_SIZE - 2 * NUMBER_SIZE).freeze();
+        Message::Piece(index, begin, block)
     }
 
-    fn port(&self, buf: &mut BytesMut) -> Option<Message> {
+    fn port(&self, buf: &mut BytesMut) -> Message {
         // port: <len=0003><id=9><listen-port>
-        if buf.len() >= SHORT_SIZE {
-            let port = BigEndian::read_u16(&buf.split_to(SHORT_SIZE));
-            Some(Message::Port(port))
-        } else {
-            None
-        }
+        let port = BigEndian::read_u16(&buf.split_to(SHORT_SIZE));
+        Message::Port(port)
+    }
+
+    fn suggest_piece(&self, buf: &mut BytesMut) -> Message {
+        // suggest piece: <len=0005><id=13><index>
+        let index = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
+        Message::SuggestPiece(index)
+    }
+
+    fn have_all(&self) -> Message {
+        // have all: <len=0001><id=14>
+        Message::HaveAll()
+    }
+
+    fn have_none(&self) -> Message {
+        // have none: <len=0001><id=15>
+        Message::HaveNone()
+    }
+
+    fn reject_request(&self, buf: &mut BytesMut) -> Message {
+        // reject request: <len=0013><id=16><index><begin><length>
+        let index = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
+        let begin = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
+        let length = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
+        Message::RejectRequest(index, begin, length)
+    }
+
+    fn allowed_fast(&self, buf: &mut BytesMut) -> Message {
+        // allowed fast: <len=0005><id=17><index>
+        let index = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
+        Message::AllowedFast(index)
+    }
+
+    fn extended(&self, buf: &mut BytesMut, len: usize) -> Message {
+        // extended: <len=0002+X><id=20><extended id><payload>
+        let id = buf.split_to(BYTE_SIZE)[0];
+        let payload = Vec::from(buf.split_to(len - 2 * BYTE_SIZE).as_ref());
+        Message::Extended(id, payload)
+    }
+
+    fn hash_request(&self, buf: &mut BytesMut) -> Message {
+        // hash request: <len=0049><id=21><pieces root><base layer><index><length><proof layers>
+        let (root, base, index, length, proof) = self.hash_header(buf);
+        Message::HashRequest(root, base, index, length, proof)
+    }
+
+    fn hashes(&self, buf: &mut BytesMut, len: usize) -> Message {
+        // hashes: <len=0049+X><id=22><pieces root><base layer><index><length><proof layers>
+        //  <hashes>
+        let (root, base, index, length, proof) = self.hash_header(buf);
+        let hashes = buf.split_to(len - BYTE_SIZE - PIECES_ROOT_LEN - 4 * NUMBER_SIZE)
+            .freeze();
+        Message::Hashes(root, base, index, length, proof, hashes)
+    }
+
+    fn hash_reject(&self, buf: &mut BytesMut) -> Message {
+        // hash reject: <len=0049><id=23><pieces root><base layer><index><length><proof layers>
+        let (root, base, index, length, proof) = self.hash_header(buf);
+        Message::HashReject(root, base, index, length, proof)
+    }
+
+    fn hash_header(&self, buf: &mut BytesMut) -> (Vec<u8>, u32, u32, u32, u32) {
+        let root = Vec::from(buf.split_to(PIECES_ROOT_LEN).as_ref());
+        let base = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
+        let index = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
+        let length = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
+        let proof = BigEndian::read_u32(&buf.split_to(NUMBER_SIZE));
+        (root, base, index, length, proof)
     }
 }
 impl Decoder for PeerCodec {
@@ -159,58 +339,41 @@ impl Decoder for PeerCodec {
         if buf.len() < 200 {
             println!("Decoder::decode() <= '{}'", &buf.to_hex());
         }
-        if buf.is_empty() {
-            return Ok(None);
-        } else if let Some(handshake) = self.handshake(buf) {
-            messages.push_back(Some(handshake));
-        } else {
-            while buf.len() >= size_of::<u32>() {
-                // println!("Decoder::decode(): buf.len(): {}", buf.len());
-                let payload_length = BigEndian::read_u32(&buf) as usize;
-                // println!("Decoder::decode(): payload_length: {}", payload_length);
-                let message_length = size_of::<u32>() + payload_length;
-                // println!("Decoder::decode(): message_length: {}", message_length);
-                if buf.len() < message_length {
-                    //println!("Decoder::decode(): rest of buf: {}", buf.to_hex());
-                    break;
+        loop {
+            match self.state {
+                State::Handshake if self.split_handshake => {
+                    match self.info_hash(buf)? {
+                        Some(msg) => {
+                            messages.push_back(Ok(msg));
+                            self.state = State::PeerId;
+                        }
+                        None => break,
+                    }
+                }
+                State::Handshake => {
+                    match self.handshake(buf)? {
+                        Some(msg) => {
+                            messages.push_back(Ok(msg));
+                            self.state = State::Frames;
+                        }
+                        None => break,
+                    }
                 }
-                // println!(
-                //     "Decoder::decode(): msg: {} ",
-                //     buf[0..message_length].to_hex()
-                // );
-
-                buf.split_to(size_of::<u32>()); // consume payload length
-
-                //thread::sleep(Duration::from_millis(30));
-                if 0 == payload_length {
-                    messages.push_back(Some(Message::KeepAlive()));
-                } else if buf.len() >= payload_length {
-                    let msg_code = buf.split_to(1)[0]; // consume cmd code
-                    // println!("Decoder::decode(): msg_code: {}", msg_code);
-                    let msg = match msg_code {
-                        CHOCKE_ID => self.choke(),
-                        UNCHOCKE_ID => self.unchoke(),
-                        INTERESTED_ID => self.interested(),
-                        NOT_INTERESTED_ID => self.not_interested(),
-                        HAVE_ID => self.have(buf),
-                        BITFIELD_ID => self.bitfield(buf, payload_length),
-                        REQUEST_ID => self.request(buf),
-                        PIECE_ID => self.piece(buf, payload_length),
-                        CANCEL_ID => self.cancel(buf),
-                        PORT_ID => self.port(buf),
-                        _ => {
-                            println!("Decoder::decode(): Unknown Message: {:X}", msg_code);
-                            None
+                State::PeerId => {
+                    match self.peer_id(buf) {
+                        Some(msg) => {
+                            messages.push_back(Ok(msg));
+                            self.state = State::Frames;
                         }
-                    };
-                    messages.push_back(msg);
+                        None => break,
+                    }
+                }
+                State::Frames => {
+                    self.frames(buf, &mut messages)?;
+                    break;
                 }
             }
         }
-        // println!("Decoder::decode() messages.len(): {}", messages.len());
-        // for msg in &messages {
-        //     println!("Decoder::decode() msg: {:?}", msg);
-        // }
         if messages.is_empty() {
             Ok(None)
         } else {
@@ -223,24 +386,22 @@ impl Encoder for PeerCodec {
     type Error = io::Error;
 
     fn encode(&mut self, msg: Message, buf: &mut BytesMut) -> io::Result<()> {
+        validate(&msg)?;
         match msg {
-            Message::Handshake(hash_info, peer_id) => {
-                if hash_info.len() != HASH_INFO_LEN {
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        "HASH INFO length shall be 20 bytes",
-                    ));
-                }
-                if peer_id.len() != PEER_ID_LEN {
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        "PEER ID length shall be 20 bytes",
-                    ));
-                }
+            Message::Handshake(reserved, hash_info, peer_id) => {
+                add_u8(buf, PSTR.len() as u8);
+                add_vec(buf, PSTR.as_bytes());
+                add_vec(buf, reserved.as_bytes());
+                add_vec(buf, handshake_hash(&hash_info));
+                add_vec(buf, &peer_id);
+            }
+            Message::InfoHash(reserved, hash_info) => {
                 add_u8(buf, PSTR.len() as u8);
                 add_vec(buf, PSTR.as_bytes());
-                add_vec(buf, &RESERVED);
-                add_vec(buf, &hash_info);
+                add_vec(buf, reserved.as_bytes());
+                add_vec(buf, handshake_hash(&hash_info));
+            }
+            Message::PeerId(peer_id) => {
                 add_vec(buf, &peer_id);
             }
             Message::KeepAlive() => {
@@ -288,11 +449,10 @@ impl Encoder for PeerCodec {
                 add_u32(buf, length);
             }
             Message::Piece(index, begin, block) => {
-                // piece: <len=0007+X><id=7><index><begin><block>
-                add_len(buf, 0x07 + block.len() as u32);
-                add_u8(buf, 0x07);
-                add_u32(buf, index);
-                add_u32(buf, begin);
+                // piece: <len=0009+X><id=7><index><begin><block>
+                // the block is copied here, PeerSession writes it without the codec instead
+                buf.reserve(PIECE_HEADER_LEN + block.len());
+                add_piece_header(buf, index, begin, block.len());
                 add_vec(buf, &block);
             }
             Message::Cancel(index, begin, length) => {
@@ -309,12 +469,149 @@ impl Encoder for PeerCodec {
                 add_u8(buf, 0x09);
                 add_u16(buf, port);
             }
+            Message::SuggestPiece(index) => {
+                // suggest piece: <len=0005><id=13><index>
+                add_len(buf, 0x05);
+                add_u8(buf, 0x0D);
+                add_u32(buf, index);
+            }
+            Message::HaveAll() => {
+                // have all: <len=0001><id=14>
+                add_len(buf, 0x01);
+                add_u8(buf, 0x0E);
+            }
+            Message::HaveNone() => {
+                // have none: <len=0001><id=15>
+                add_len(buf, 0x01);
+                add_u8(buf, 0x0F);
+            }
+            Message::RejectRequest(index, begin, length) => {
+                // reject request: <len=0013><id=16><index><begin><length>
+                add_len(buf, 0x0D);
+                add_u8(buf, 0x10);
+                add_u32(buf, index);
+                add_u32(buf, begin);
+                add_u32(buf, length);
+            }
+            Message::AllowedFast(index) => {
+                // allowed fast: <len=0005><id=17><index>
+                add_len(buf, 0x05);
+                add_u8(buf, 0x11);
+                add_u32(buf, index);
+            }
+            Message::Extended(id, payload) => {
+                // extended: <len=0002+X><id=20><extended id><payload>
+                add_len(buf, 0x02 + payload.len() as u32);
+                add_u8(buf, 0x14);
+                add_u8(buf, id);
+                add_vec(buf, &payload);
+            }
+            Message::HashRequest(root, base, index, length, proof) => {
+                // hash request: <len=0049><id=21><pieces root><base layer><index><length>
+                //  <proof layers>
+                add_len(buf, 0x31);
+                add_u8(buf, 0x15);
+                add_hash_header(buf, &root, base, index, length, proof);
+            }
+            Message::Hashes(root, base, index, length, proof, hashes) => {
+                // hashes: <len=0049+X><id=22><pieces root><base layer><index><length>
+                //  <proof layers><hashes>
+                add_len(buf, 0x31 + hashes.len() as u32);
+                add_u8(buf, 0x16);
+                add_hash_header(buf, &root, base, index, length, proof);
+                add_vec(buf, &hashes);
+            }
+            Message::HashReject(root, base, index, length, proof) => {
+                // hash reject: <len=0049><id=23><pieces root><base layer><index><length>
+                //  <proof layers>
+                add_len(buf, 0x31);
+                add_u8(buf, 0x17);
+                add_hash_header(buf, &root, base, index, length, proof);
+            }
         }
         // println!("Encoder::encode() => '{}'", &buf.to_hex());
         Ok(())
     }
 }
 
+/// checks that the payload length matches the message id
+fn check_length(id: u8, length: usize) -> Result<(), PeerError> {
+    let valid = match id {
+        CHOCKE_ID | UNCHOCKE_ID | INTERESTED_ID | NOT_INTERESTED_ID | HAVE_ALL_ID |
+        HAVE_NONE_ID => length == BYTE_SIZE,
+        HAVE_ID | SUGGEST_PIECE_ID | ALLOWED_FAST_ID => length == BYTE_SIZE + NUMBER_SIZE,
+        REQUEST_ID | CANCEL_ID | REJECT_REQUEST_ID => length == BYTE_SIZE + 3 * NUMBER_SIZE,
+        PORT_ID => length == BYTE_SIZE + SHORT_SIZE,
+        PIECE_ID => length >= BYTE_SIZE + 2 * NUMBER_SIZE,
+        EXTENDED_ID => length >= 2 * BYTE_SIZE,
+        HASH_REQUEST_ID | HASH_REJECT_ID => {
+            length == BYTE_SIZE + PIECES_ROOT_LEN + 4 * NUMBER_SIZE
+        }
+        HASHES_ID => {
+            length >= BYTE_SIZE + PIECES_ROOT_LEN + 4 * NUMBER_SIZE &&
+                0 == (length - BYTE_SIZE - PIECES_ROOT_LEN - 4 * NUMBER_SIZE) % HASH_LEN
+        }
+        _ => true,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(PeerError::MalformedLength(id, length))
+    }
+}
+
+/// checks that the message can be sent to the peer
+pub fn validate(msg: &Message) -> Result<(), PeerError> {
+    match msg {
+        &Message::Handshake(_, ref hash_info, ref peer_id) => {
+            if hash_info.len() != HASH_INFO_LEN && hash_info.len() != HASH_INFO_V2_LEN {
+                return Err(PeerError::BadHandshake("HASH INFO length shall be 20 or 32 bytes"));
+            }
+            if peer_id.len() != PEER_ID_LEN {
+                return Err(PeerError::BadHandshake("PEER ID length shall be 20 bytes"));
+            }
+        }
+        &Message::InfoHash(_, ref hash_info) => {
+            if hash_info.len() != HASH_INFO_LEN && hash_info.len() != HASH_INFO_V2_LEN {
+                return Err(PeerError::BadHandshake("HASH INFO length shall be 20 or 32 bytes"));
+            }
+        }
+        &Message::HashRequest(ref root, _, _, _, _) |
+        &Message::HashReject(ref root, _, _, _, _) => {
+            if root.len() != PIECES_ROOT_LEN {
+                return Err(PeerError::ProtocolViolation("Pieces root shall be 32 bytes"));
+            }
+        }
+        &Message::Hashes(ref root, _, _, _, _, ref hashes) => {
+            if root.len() != PIECES_ROOT_LEN {
+                return Err(PeerError::ProtocolViolation("Pieces root shall be 32 bytes"));
+            }
+            if 0 != hashes.len() % HASH_LEN {
+                return Err(PeerError::ProtocolViolation("Hashes shall be a multiple of 32 bytes"));
+            }
+        }
+        &Message::PeerId(ref peer_id) => {
+            if peer_id.len() != PEER_ID_LEN {
+                return Err(PeerError::BadHandshake("PEER ID length shall be 20 bytes"));
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// returns the header of the piece message, the block shall follow it
+pub fn piece_header(index: u32, begin: u32, length: usize) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(PIECE_HEADER_LEN);
+    add_piece_header(&mut buf, index, begin, length);
+    buf
+}
+
+/// returns the info hash as it is sent in the handshake, v2 hashes are truncated to 20 bytes
+pub fn handshake_hash(info_hash: &[u8]) -> &[u8] {
+    &info_hash[..cmp::min(info_hash.len(), HASH_INFO_LEN)]
+}
+
 fn add_u8(buf: &mut BytesMut, id: u8) {
     let container = [id; size_of::<u8>()];
     buf.extend_from_slice(&container);
@@ -339,3 +636,162 @@ fn add_u16(buf: &mut BytesMut, value: u16) {
 fn add_vec(buf: &mut BytesMut, container: &[u8]) {
     buf.extend_from_slice(container);
 }
+
+fn add_piece_header(buf: &mut BytesMut, index: u32, begin: u32, length: usize) {
+    add_len(buf, 0x09 + length as u32);
+    add_u8(buf, 0x07);
+    add_u32(buf, index);
+    add_u32(buf, begin);
+}
+
+fn add_hash_header(buf: &mut BytesMut, root: &[u8], base: u32, index: u32, length: u32, proof: u32) {
+    add_vec(buf, root);
+    add_u32(buf, base);
+    add_u32(buf, index);
+    add_u32(buf, length);
+    add_u32(buf, proof);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// returns codec which has passed the handshake
+    fn frames_codec(max_frame_size: usize) -> PeerCodec {
+        let mut codec = PeerCodec::with_max_frame_size(max_frame_size);
+        codec.state = State::Frames;
+        codec
+    }
+
+    fn decode(codec: &mut PeerCodec, buf: &mut BytesMut) -> Result<Vec<Message>, PeerError> {
+        match codec.decode(buf) {
+            Ok(messages) => messages.unwrap_or_default().into_iter().collect(),
+            Err(err) => Err(PeerError::from(err)),
+        }
+    }
+
+    #[test]
+    fn malformed_length() {
+        let frames: &[&[u8]] = &[
+            // choke with a payload
+            b"\x00\x00\x00\x02\x00\x00",
+            // have without the index
+            b"\x00\x00\x00\x01\x04",
+            // request without the length
+            b"\x00\x00\x00\x09\x06\x00\x00\x00\x01\x00\x00\x00\x00",
+            // piece without the begin
+            b"\x00\x00\x00\x05\x07\x00\x00\x00\x01",
+            // hashes with a partial hash
+            &[&b"\x00\x00\x00\x32\x16"[..], &[0u8; 49][..]].concat(),
+        ];
+        for frame in frames {
+            let mut codec = frames_codec(MAX_FRAME_SIZE);
+            match decode(&mut codec, &mut BytesMut::from(*frame)) {
+                Err(PeerError::MalformedLength(id, length)) => {
+                    assert_eq!(frame[4], id);
+                    assert_eq!(frame.len() - NUMBER_SIZE, length);
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_frame() {
+        let mut codec = frames_codec(0x10);
+        // only the length prefix is received, the payload isn't awaited
+        let mut buf = BytesMut::from(&b"\x00\x00\x00\x11"[..]);
+        match decode(&mut codec, &mut buf) {
+            Err(PeerError::OversizedFrame(0x11, 0x10)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        let mut buf = BytesMut::from(&b"\x00\x00\x00\x10\x05"[..]);
+        assert_eq!(Ok(Vec::new()), decode(&mut codec, &mut buf).map_err(|_| ()));
+        assert_eq!(5, buf.len());
+    }
+
+    #[test]
+    fn keep_alive() {
+        let mut codec = frames_codec(MAX_FRAME_SIZE);
+        let mut buf = BytesMut::from(&b"\x00\x00\x00\x00\x00\x00\x00\x05\x04\x00\x00\x00\x07"[..]);
+        assert_eq!(
+            vec![Message::KeepAlive(), Message::Have(7)],
+            decode(&mut codec, &mut buf).map_err(|_| ()).unwrap()
+        );
+        assert!(buf.is_empty());
+    }
+
+    fn handshake() -> BytesMut {
+        let mut buf = BytesMut::new();
+        let msg = Message::Handshake(Reserved::new(), vec![1; 20], vec![2; 20]);
+        PeerCodec::new().encode(msg, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn handshake_across_reads() {
+        let data = handshake();
+        let mut codec = PeerCodec::new();
+        let mut buf = BytesMut::new();
+        for chunk in data.chunks(30) {
+            assert_eq!(State::Handshake, codec.state);
+            buf.extend_from_slice(chunk);
+            let messages = decode(&mut codec, &mut buf).map_err(|_| ()).unwrap();
+            if buf.is_empty() {
+                assert_eq!(
+                    vec![Message::Handshake(Reserved::new(), vec![1; 20], vec![2; 20])],
+                    messages
+                );
+            } else {
+                assert!(messages.is_empty());
+            }
+        }
+        assert_eq!(State::Frames, codec.state);
+    }
+
+    #[test]
+    fn late_peer_id() {
+        let data = handshake();
+        let mut codec = PeerCodec::new();
+        codec.set_split_handshake(true);
+        let mut buf = BytesMut::from(&data[..58]);
+        assert_eq!(
+            vec![Message::InfoHash(Reserved::new(), vec![1; 20])],
+            decode(&mut codec, &mut buf).map_err(|_| ()).unwrap()
+        );
+        assert_eq!(State::PeerId, codec.state);
+        assert_eq!(10, buf.len());
+        buf.extend_from_slice(&data[58..]);
+        assert_eq!(
+            vec![Message::PeerId(vec![2; 20])],
+            decode(&mut codec, &mut buf).map_err(|_| ()).unwrap()
+        );
+        assert_eq!(State::Frames, codec.state);
+    }
+
+    #[test]
+    fn frames_after_handshake() {
+        let mut data = handshake();
+        data.extend_from_slice(b"\x00\x00\x00\x05\x04\x00\x00\x00\x03\x00\x00\x00\x01\x01");
+        let mut codec = PeerCodec::new();
+        assert_eq!(
+            vec![
+                Message::Handshake(Reserved::new(), vec![1; 20], vec![2; 20]),
+                Message::Have(3),
+                Message::Unchoke(),
+            ],
+            decode(&mut codec, &mut data.clone()).map_err(|_| ()).unwrap()
+        );
+        let mut codec = PeerCodec::new();
+        codec.set_split_handshake(true);
+        assert_eq!(
+            vec![
+                Message::InfoHash(Reserved::new(), vec![1; 20]),
+                Message::PeerId(vec![2; 20]),
+                Message::Have(3),
+                Message::Unchoke(),
+            ],
+            decode(&mut codec, &mut data).map_err(|_| ()).unwrap()
+        );
+    }
+}
@@ -0,0 +1,393 @@
+use std::io;
+use std::cmp;
+use std::fs;
+use std::sync::Arc;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bencode::Value;
+use hash::{sha1, merkle_leaves, file_root, piece_layer, Sha1, Sha256};
+use metainfo::{Metainfo, MetainfoError};
+use parallel::run_parallel;
+
+const MIN_PIECE_LENGTH: u32 = 0x4000;
+const MAX_PIECE_LENGTH: u32 = 0x1000000;
+/// Automatic piece length keeps the piece count about this value
+const TARGET_PIECE_COUNT: u64 = 1500;
+const HASH_THREADS: usize = 4;
+
+/// File of the new torrent
+struct Source {
+    path: PathBuf,
+    /// path relative to the torrent root
+    components: Vec<String>,
+    length: u64,
+}
+
+/// Kind of the torrent the builder creates
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Version {
+    /// SHA-1 pieces spanning the concatenated files (BEP 3)
+    V1,
+    /// SHA-256 merkle tree of every file (BEP 52)
+    V2,
+    /// both, v1 files are padded to the piece boundary so the pieces are the same (BEP 47)
+    Hybrid,
+}
+
+/// Hashes of the file aligned to the piece boundary
+struct FileHashes {
+    /// v1 pieces of the hybrid torrent
+    pieces: Vec<Sha1>,
+    /// SHA-256 of the 16 KiB blocks
+    leaves: Vec<Sha256>,
+}
+
+/// Creates .torrent from a local file or directory
+pub struct TorrentBuilder {
+    root: PathBuf,
+    piece_length: Option<u32>,
+    announce_list: Vec<Vec<String>>,
+    web_seeds: Vec<String>,
+    private: bool,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: bool,
+    threads: usize,
+    version: Version,
+}
+
+impl TorrentBuilder {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        TorrentBuilder {
+            root: root.as_ref().to_path_buf(),
+            piece_length: None,
+            announce_list: Vec::new(),
+            web_seeds: Vec::new(),
+            private: false,
+            comment: None,
+            created_by: None,
+            creation_date: true,
+            threads: HASH_THREADS,
+            version: Version::V1,
+        }
+    }
+
+    /// sets the piece length, power of two not less than 16 KiB, it's chosen by size otherwise
+    pub fn set_piece_length(&mut self, length: u32) {
+        self.piece_length = Some(length);
+    }
+
+    /// adds tier of trackers, the first tracker of the first tier becomes the announce URL
+    pub fn add_tier(&mut self, trackers: Vec<String>) {
+        if !trackers.is_empty() {
+            self.announce_list.push(trackers);
+        }
+    }
+
+    pub fn add_web_seed(&mut self, url: &str) {
+        self.web_seeds.push(url.to_string());
+    }
+
+    pub fn set_private(&mut self, private: bool) {
+        self.private = private;
+    }
+
+    pub fn set_comment(&mut self, comment: &str) {
+        self.comment = Some(comment.to_string());
+    }
+
+    pub fn set_created_by(&mut self, created_by: &str) {
+        self.created_by = Some(created_by.to_string());
+    }
+
+    /// if disabled the torrent is reproducible, same content gives the same file
+    pub fn set_creation_date(&mut self, enabled: bool) {
+        self.creation_date = enabled;
+    }
+
+    /// sets number of the threads hashing pieces
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = cmp::max(1, threads);
+    }
+
+    /// v1 torrent is created by default
+    pub fn set_version(&mut self, version: Version) {
+        self.version = version;
+    }
+
+    /// hashes the content and returns the metainfo, Metainfo::encode gives the .torrent file
+    pub fn build(&self) -> Result<Metainfo, MetainfoError> {
+        let name = self.root
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or(MetainfoError::Invalid("root has no UTF-8 file name"))?
+            .to_string();
+        let multi_file = fs::metadata(&self.root)?.is_dir();
+        let mut sources = Vec::new();
+        if multi_file {
+            walk(&self.root, &mut Vec::new(), &mut sources)?;
+        } else {
+            let length = fs::metadata(&self.root)?.len();
+            sources.push(Source {
+                path: self.root.clone(),
+                components: vec![name.clone()],
+                length: length,
+            });
+        }
+        let total = sources.iter().map(|source| source.length).sum::<u64>();
+        if 0 == total {
+            return Err(MetainfoError::Invalid("nothing to share, content is empty"));
+        }
+        let piece_length = match self.piece_length {
+            Some(length) => {
+                if length < MIN_PIECE_LENGTH || !length.is_power_of_two() {
+                    return Err(MetainfoError::Invalid(
+                        "piece length shall be a power of two not less than 16 KiB",
+                    ));
+                }
+                length
+            }
+            None => auto_piece_length(total),
+        };
+
+        let sources = Arc::new(sources);
+        let mut info = BTreeMap::new();
+        let mut piece_layers = BTreeMap::new();
+        let pieces = if Version::V1 == self.version {
+            Some(hash_pieces(sources.clone(), piece_length, self.threads)?)
+        } else {
+            let hybrid = Version::Hybrid == self.version;
+            let hashes = hash_files(sources.clone(), piece_length, self.threads, hybrid)?;
+            let mut tree = BTreeMap::new();
+            let mut pieces = Vec::new();
+            for (source, hashes) in sources.iter().zip(hashes) {
+                let mut file = BTreeMap::new();
+                file.insert(Vec::from(&b"length"[..]), Value::Int(source.length as i64));
+                if source.length > 0 {
+                    let root = file_root(&hashes.leaves);
+                    if source.length > piece_length as u64 {
+                        let layer = piece_layer(&hashes.leaves, piece_length as usize);
+                        piece_layers.insert(root.clone(), layer.concat());
+                    }
+                    file.insert(Vec::from(&b"pieces root"[..]), Value::Bytes(root));
+                }
+                insert_file(&mut tree, &source.components, Value::Dict(file));
+                pieces.extend(hashes.pieces);
+            }
+            info.insert(Vec::from(&b"file tree"[..]), Value::Dict(tree));
+            info.insert(Vec::from(&b"meta version"[..]), Value::Int(2));
+            if hybrid { Some(pieces) } else { None }
+        };
+        if let Some(pieces) = pieces {
+            if multi_file {
+                let last = last_non_empty(&sources);
+                let mut files = Vec::new();
+                for (index, source) in sources.iter().enumerate() {
+                    let mut file = BTreeMap::new();
+                    file.insert(Vec::from(&b"length"[..]), Value::Int(source.length as i64));
+                    let path = source.components.iter().map(|c| Value::from(c.as_str())).collect();
+                    file.insert(Vec::from(&b"path"[..]), Value::List(path));
+                    files.push(Value::Dict(file));
+                    let tail = source.length % piece_length as u64;
+                    if Version::Hybrid == self.version && index < last && 0 != tail {
+                        files.push(pad_file(piece_length as u64 - tail));
+                    }
+                }
+                info.insert(Vec::from(&b"files"[..]), Value::List(files));
+            } else {
+                info.insert(Vec::from(&b"length"[..]), Value::Int(total as i64));
+            }
+            info.insert(Vec::from(&b"pieces"[..]), Value::Bytes(pieces.concat()));
+        }
+        info.insert(Vec::from(&b"name"[..]), Value::from(name.as_str()));
+        info.insert(Vec::from(&b"piece length"[..]), Value::Int(piece_length as i64));
+        if self.private {
+            info.insert(Vec::from(&b"private"[..]), Value::Int(1));
+        }
+
+        let mut metainfo = Metainfo::from_info(&Value::Dict(info).encode())?;
+        metainfo.announce = self.announce_list.first().map(|tier| tier[0].clone());
+        if self.announce_list.len() > 1 || self.announce_list.iter().any(|t| t.len() > 1) {
+            metainfo.announce_list = self.announce_list.clone();
+        }
+        metainfo.piece_layers = piece_layers;
+        metainfo.url_list = self.web_seeds.clone();
+        metainfo.comment = self.comment.clone();
+        metainfo.created_by = self.created_by.clone();
+        if self.creation_date {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs());
+            metainfo.creation_date = now.ok().map(|secs| secs as i64);
+        }
+        Ok(metainfo)
+    }
+}
+
+/// collects files of the directory in the sorted order, symlinks are not followed
+fn walk(dir: &Path, prefix: &mut Vec<String>, sources: &mut Vec<Source>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "file name is not UTF-8"))
+            }
+        };
+        // symlinks are skipped, they may point outside the directory or form a loop
+        let metadata = fs::symlink_metadata(entry.path())?;
+        if metadata.file_type().is_symlink() {
+            continue;
+        }
+        prefix.push(name);
+        if metadata.is_dir() {
+            walk(&entry.path(), prefix, sources)?;
+        } else {
+            sources.push(Source {
+                path: entry.path(),
+                components: prefix.clone(),
+                length: metadata.len(),
+            });
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+fn auto_piece_length(total: u64) -> u32 {
+    let mut length = MIN_PIECE_LENGTH;
+    while length < MAX_PIECE_LENGTH && total / length as u64 > TARGET_PIECE_COUNT {
+        length *= 2;
+    }
+    length
+}
+
+/// hashes pieces which span the files one after another
+fn hash_pieces(sources: Arc<Vec<Source>>, piece_length: u32, threads: usize) -> io::Result<Vec<Sha1>> {
+    let total = sources.iter().map(|source| source.length).sum::<u64>();
+    let count = ((total + piece_length as u64 - 1) / piece_length as u64) as usize;
+    run_parallel(
+        count,
+        threads,
+        Vec::new,
+        move |buf, index| {
+            let offset = index as u64 * piece_length as u64;
+            let len = cmp::min(piece_length as u64, total - offset);
+            read_span(&sources, offset, len, buf)?;
+            Ok(sha1(buf))
+        },
+        |_, _| true,
+    )
+}
+
+/// hashes every file on its own with pieces starting at the file start,
+/// for v1 the last piece is padded with zeros unless no data follows the file
+fn hash_files(
+    sources: Arc<Vec<Source>>,
+    piece_length: u32,
+    threads: usize,
+    v1: bool,
+) -> io::Result<Vec<FileHashes>> {
+    let last = last_non_empty(&sources);
+    let mut items = Vec::new();
+    for (file, source) in sources.iter().enumerate() {
+        let count = (source.length + piece_length as u64 - 1) / piece_length as u64;
+        items.extend((0..count).map(|piece| (file, piece)));
+    }
+    let items = Arc::new(items);
+    let results = {
+        let items = items.clone();
+        let sources = sources.clone();
+        run_parallel(
+            items.len(),
+            threads,
+            Vec::new,
+            move |buf, item| {
+                let (file, piece) = items[item];
+                let offset = piece * piece_length as u64;
+                let len = cmp::min(piece_length as u64, sources[file].length - offset);
+                read_span(&sources[file..file + 1], offset, len, buf)?;
+                let leaves = merkle_leaves(buf);
+                let hash = if v1 {
+                    if file < last {
+                        buf.resize(piece_length as usize, 0);
+                    }
+                    Some(sha1(buf))
+                } else {
+                    None
+                };
+                Ok((hash, leaves))
+            },
+            |_, _| true,
+        )?
+    };
+    let mut files = sources
+        .iter()
+        .map(|_| {
+            FileHashes {
+                pieces: Vec::new(),
+                leaves: Vec::new(),
+            }
+        })
+        .collect::<Vec<_>>();
+    for (&(file, _), (hash, leaves)) in items.iter().zip(results) {
+        files[file].pieces.extend(hash);
+        files[file].leaves.extend(leaves);
+    }
+    Ok(files)
+}
+
+/// returns index of the last file with data, files after it need no padding
+fn last_non_empty(sources: &[Source]) -> usize {
+    sources.iter().rposition(|source| source.length > 0).unwrap_or(0)
+}
+
+/// puts the file into the v2 'file tree', the file is the value of the empty key
+fn insert_file(tree: &mut BTreeMap<Vec<u8>, Value>, path: &[String], file: Value) {
+    let key = Vec::from(path[0].as_bytes());
+    if 1 == path.len() {
+        let mut node = BTreeMap::new();
+        node.insert(Vec::new(), file);
+        tree.insert(key, Value::Dict(node));
+    } else {
+        let node = tree.entry(key).or_insert_with(|| Value::Dict(BTreeMap::new()));
+        if let &mut Value::Dict(ref mut node) = node {
+            insert_file(node, &path[1..], file);
+        }
+    }
+}
+
+/// returns BEP 47 padding file of the given length
+fn pad_file(length: u64) -> Value {
+    let mut file = BTreeMap::new();
+    file.insert(Vec::from(&b"attr"[..]), Value::from("p"));
+    file.insert(Vec::from(&b"length"[..]), Value::Int(length as i64));
+    let path = vec![Value::from(".pad"), Value::from(length.to_string().as_str())];
+    file.insert(Vec::from(&b"path"[..]), Value::List(path));
+    Value::Dict(file)
+}
+
+/// reads len bytes at the offset of the concatenated files
+fn read_span(sources: &[Source], mut offset: u64, len: u64, buf: &mut Vec<u8>) -> io::Result<()> {
+    buf.clear();
+    for source in sources {
+        if buf.len() as u64 == len {
+            break;
+        }
+        if offset >= source.length {
+            offset -= source.length;
+            continue;
+        }
+        let chunk = cmp::min(source.length - offset, len - buf.len() as u64);
+        let mut file = fs::File::open(&source.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let read = file.take(chunk).read_to_end(buf)?;
+        if read as u64 != chunk {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file has shrunk"));
+        }
+        offset = 0;
+    }
+    Ok(())
+}
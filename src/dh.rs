@@ -0,0 +1,146 @@
+use rand::{self, Rng};
+
+/// Length of the public key and the shared secret in bytes
+pub const KEY_LEN: usize = 96;
+/// Length of the private key in bytes
+const PRIVATE_KEY_LEN: usize = 20;
+const LIMBS: usize = KEY_LEN / 4;
+
+// 768 bit prime of the Message Stream Encryption, generator is 2
+const PRIME: [u8; KEY_LEN] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xC9, 0x0F, 0xDA, 0xA2,
+    0x21, 0x68, 0xC2, 0x34, 0xC4, 0xC6, 0x62, 0x8B, 0x80, 0xDC, 0x1C, 0xD1,
+    0x29, 0x02, 0x4E, 0x08, 0x8A, 0x67, 0xCC, 0x74, 0x02, 0x0B, 0xBE, 0xA6,
+    0x3B, 0x13, 0x9B, 0x22, 0x51, 0x4A, 0x08, 0x79, 0x8E, 0x34, 0x04, 0xDD,
+    0xEF, 0x95, 0x19, 0xB3, 0xCD, 0x3A, 0x43, 0x1B, 0x30, 0x2B, 0x0A, 0x6D,
+    0xF2, 0x5F, 0x14, 0x37, 0x4F, 0xE1, 0x35, 0x6D, 0x6D, 0x51, 0xC2, 0x45,
+    0xE4, 0x85, 0xB5, 0x76, 0x62, 0x5E, 0x7E, 0xC6, 0xF4, 0x4C, 0x42, 0xE9,
+    0xA6, 0x3A, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63,
+];
+const GENERATOR: u32 = 2;
+
+/// Diffie-Hellman key pair of the Message Stream Encryption
+pub struct KeyPair {
+    private: Vec<u8>,
+    public: Vec<u8>,
+}
+
+impl KeyPair {
+    /// generates random 160 bit private key and the public one
+    pub fn new() -> Self {
+        let mut private = vec![0u8; PRIVATE_KEY_LEN];
+        rand::thread_rng().fill_bytes(&mut private);
+        let mut generator = [0u32; LIMBS];
+        generator[0] = GENERATOR;
+        let public = to_bytes(&pow_mod(&generator, &private));
+        KeyPair {
+            private: private,
+            public: public,
+        }
+    }
+
+    /// returns public key as 96 bytes big endian number
+    pub fn public(&self) -> &[u8] {
+        &self.public
+    }
+
+    /// returns shared secret as 96 bytes big endian number
+    pub fn secret(&self, remote: &[u8]) -> Vec<u8> {
+        to_bytes(&pow_mod(&from_bytes(remote), &self.private))
+    }
+}
+
+fn from_bytes(bytes: &[u8]) -> [u32; LIMBS] {
+    let mut num = [0u32; LIMBS];
+    for (i, byte) in bytes.iter().rev().take(KEY_LEN).enumerate() {
+        num[i / 4] |= (*byte as u32) << (8 * (i % 4));
+    }
+    num
+}
+
+fn to_bytes(num: &[u32]) -> Vec<u8> {
+    let mut bytes = vec![0u8; KEY_LEN];
+    for i in 0..KEY_LEN {
+        bytes[KEY_LEN - 1 - i] = (num[i / 4] >> (8 * (i % 4))) as u8;
+    }
+    bytes
+}
+
+/// returns base^exponent mod PRIME, exponent is a big endian number
+fn pow_mod(base: &[u32; LIMBS], exponent: &[u8]) -> [u32; LIMBS] {
+    let prime = from_bytes(&PRIME);
+    let base = mul_mod(base, &one(), &prime);
+    let mut result = one();
+    for byte in exponent {
+        for bit in (0..8).rev() {
+            result = mul_mod(&result, &result, &prime);
+            if 0 != (byte >> bit) & 1 {
+                result = mul_mod(&result, &base, &prime);
+            }
+        }
+    }
+    result
+}
+
+fn one() -> [u32; LIMBS] {
+    let mut num = [0u32; LIMBS];
+    num[0] = 1;
+    num
+}
+
+/// returns a * b mod m, the product is reduced bit by bit
+fn mul_mod(a: &[u32; LIMBS], b: &[u32; LIMBS], m: &[u32; LIMBS]) -> [u32; LIMBS] {
+    let mut product = [0u32; 2 * LIMBS];
+    for i in 0..LIMBS {
+        let mut carry = 0u64;
+        for j in 0..LIMBS {
+            let sum = product[i + j] as u64 + a[i] as u64 * b[j] as u64 + carry;
+            product[i + j] = sum as u32;
+            carry = sum >> 32;
+        }
+        product[i + LIMBS] = carry as u32;
+    }
+    // remainder stays below 2 * m, so one extra limb is enough
+    let mut rem = [0u32; LIMBS + 1];
+    for bit in (0..2 * LIMBS * 32).rev() {
+        let mut carry = (product[bit / 32] >> (bit % 32)) & 1;
+        for limb in rem.iter_mut() {
+            let next = *limb >> 31;
+            *limb = (*limb << 1) | carry;
+            carry = next;
+        }
+        if !less(&rem, m) {
+            sub(&mut rem, m);
+        }
+    }
+    let mut result = [0u32; LIMBS];
+    result.copy_from_slice(&rem[..LIMBS]);
+    result
+}
+
+fn less(a: &[u32; LIMBS + 1], b: &[u32; LIMBS]) -> bool {
+    if 0 != a[LIMBS] {
+        return false;
+    }
+    for i in (0..LIMBS).rev() {
+        if a[i] != b[i] {
+            return a[i] < b[i];
+        }
+    }
+    false
+}
+
+fn sub(a: &mut [u32; LIMBS + 1], b: &[u32; LIMBS]) {
+    let mut borrow = 0i64;
+    for i in 0..LIMBS + 1 {
+        let rhs = if i < LIMBS { b[i] as i64 } else { 0 };
+        let diff = a[i] as i64 - rhs - borrow;
+        if diff < 0 {
+            a[i] = (diff + (1i64 << 32)) as u32;
+            borrow = 1;
+        } else {
+            a[i] = diff as u32;
+            borrow = 0;
+        }
+    }
+}
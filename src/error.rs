@@ -0,0 +1,101 @@
+use std::io;
+use std::fmt;
+use std::error;
+use rustc_serialize::hex::ToHex;
+
+/// Errors of the peer wire protocol
+#[derive(Debug)]
+pub enum PeerError {
+    /// handshake is malformed or can't be sent
+    BadHandshake(&'static str),
+    /// peer has answered with another info hash (expected, received)
+    InfoHashMismatch(Vec<u8>, Vec<u8>),
+    /// incoming peer wants a torrent which isn't served
+    UnknownInfoHash(Vec<u8>),
+    /// length prefix exceeds the frame size limit (length, limit)
+    OversizedFrame(usize, usize),
+    /// payload length doesn't match the message (message id, payload length)
+    MalformedLength(u8, usize),
+    /// message id is not known to the codec
+    UnknownMessage(u8),
+    /// message is well formed but not allowed in the current state
+    ProtocolViolation(&'static str),
+    /// stream encryption handshake has failed
+    Encryption(&'static str),
+    /// peer hasn't done it in time
+    Timeout(&'static str),
+    Io(io::Error),
+}
+
+impl fmt::Display for PeerError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &PeerError::BadHandshake(ref reason) => write!(fmt, "Bad handshake: {}", reason),
+            &PeerError::InfoHashMismatch(ref expected, ref received) => {
+                write!(
+                    fmt,
+                    "Unexpected INFO hash: expected [{}], received [{}]",
+                    expected.to_hex(),
+                    received.to_hex()
+                )
+            }
+            &PeerError::UnknownInfoHash(ref info_hash) => {
+                write!(fmt, "Unknown INFO hash [{}]", info_hash.to_hex())
+            }
+            &PeerError::OversizedFrame(ref length, ref limit) => {
+                write!(fmt, "Frame of {} bytes exceeds limit of {} bytes", length, limit)
+            }
+            &PeerError::MalformedLength(ref id, ref length) => {
+                write!(fmt, "Malformed payload length {} of message {}", length, id)
+            }
+            &PeerError::UnknownMessage(ref id) => write!(fmt, "Unknown message id {}", id),
+            &PeerError::ProtocolViolation(ref reason) => {
+                write!(fmt, "Protocol violation: {}", reason)
+            }
+            &PeerError::Encryption(ref reason) => write!(fmt, "Encryption: {}", reason),
+            &PeerError::Timeout(ref reason) => write!(fmt, "Timeout: {}", reason),
+            &PeerError::Io(ref err) => write!(fmt, "I/O error: {}", err),
+        }
+    }
+}
+
+impl error::Error for PeerError {
+    fn description(&self) -> &str {
+        match self {
+            &PeerError::BadHandshake(_) => "bad handshake",
+            &PeerError::InfoHashMismatch(_, _) => "info hash mismatch",
+            &PeerError::UnknownInfoHash(_) => "unknown info hash",
+            &PeerError::OversizedFrame(_, _) => "oversized frame",
+            &PeerError::MalformedLength(_, _) => "malformed payload length",
+            &PeerError::UnknownMessage(_) => "unknown message id",
+            &PeerError::ProtocolViolation(_) => "protocol violation",
+            &PeerError::Encryption(_) => "encryption handshake failure",
+            &PeerError::Timeout(_) => "timeout",
+            &PeerError::Io(_) => "I/O error",
+        }
+    }
+}
+
+/// Recovers PeerError which was passed through the transport as io::Error
+impl From<io::Error> for PeerError {
+    fn from(err: io::Error) -> PeerError {
+        let wrapped = err.get_ref().map_or(false, |inner| inner.is::<PeerError>());
+        if wrapped {
+            if let Some(Ok(inner)) = err.into_inner().map(|inner| inner.downcast::<PeerError>()) {
+                return *inner;
+            }
+            unreachable!();
+        }
+        PeerError::Io(err)
+    }
+}
+
+/// Transports (Framed, tokio-proto) require io::Error, so PeerError is wrapped into it
+impl From<PeerError> for io::Error {
+    fn from(err: PeerError) -> io::Error {
+        match err {
+            PeerError::Io(err) => err,
+            err => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::str;
+use std::collections::BTreeMap;
+
+use bencode::{self, Value};
+
+/// Extended message id of the extension handshake (BEP 10)
+pub const HANDSHAKE_ID: u8 = 0;
+
+/// Bencoded dictionary exchanged as the extended message 0
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct ExtendedHandshake {
+    /// extension name to extended message id
+    pub m: BTreeMap<String, u8>,
+    /// client name and version
+    pub v: Option<String>,
+    /// number of outstanding requests the client supports
+    pub reqq: Option<u32>,
+    /// compact address the receiver is seen from
+    pub yourip: Option<Vec<u8>>,
+    /// size of the info dictionary (BEP 9)
+    pub metadata_size: Option<u32>,
+}
+
+impl ExtendedHandshake {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// returns extended message id of the extension or None if it's not supported
+    pub fn id(&self, name: &str) -> Option<u8> {
+        match self.m.get(name) {
+            Some(&0) | None => None,
+            Some(&id) => Some(id),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut m = BTreeMap::new();
+        for (name, id) in &self.m {
+            m.insert(Vec::from(name.as_bytes()), Value::Int(*id as i64));
+        }
+        let mut dict = BTreeMap::new();
+        dict.insert(Vec::from(&b"m"[..]), Value::Dict(m));
+        if let Some(size) = self.metadata_size {
+            dict.insert(Vec::from(&b"metadata_size"[..]), Value::Int(size as i64));
+        }
+        if let Some(reqq) = self.reqq {
+            dict.insert(Vec::from(&b"reqq"[..]), Value::Int(reqq as i64));
+        }
+        if let Some(ref v) = self.v {
+            dict.insert(Vec::from(&b"v"[..]), Value::from(v.as_str()));
+        }
+        if let Some(ref ip) = self.yourip {
+            dict.insert(Vec::from(&b"yourip"[..]), Value::from(ip.as_slice()));
+        }
+        Value::Dict(dict).encode()
+    }
+
+    /// parses bencoded dictionary, unknown keys are ignored
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let dict = bencode::decode_lenient(data).ok()?;
+        if dict.as_dict().is_none() {
+            return None;
+        }
+        let mut handshake = Self::new();
+        if let Some(m) = dict.get("m").and_then(Value::as_dict) {
+            for (name, id) in m {
+                if let (Ok(name), Some(id)) = (str::from_utf8(name), id.as_int()) {
+                    if id >= 0 && id <= u8::max_value() as i64 {
+                        handshake.m.insert(name.to_string(), id as u8);
+                    }
+                }
+            }
+        }
+        if let Some(v) = dict.get("v").and_then(Value::as_bytes) {
+            handshake.v = Some(String::from_utf8_lossy(v).into_owned());
+        }
+        if let Some(reqq) = dict.get("reqq").and_then(Value::as_int) {
+            if reqq >= 0 && reqq <= u32::max_value() as i64 {
+                handshake.reqq = Some(reqq as u32);
+            }
+        }
+        if let Some(ip) = dict.get("yourip").and_then(Value::as_bytes) {
+            handshake.yourip = Some(Vec::from(ip));
+        }
+        if let Some(size) = dict.get("metadata_size").and_then(Value::as_int) {
+            if size >= 0 && size <= u32::max_value() as i64 {
+                handshake.metadata_size = Some(size as u32);
+            }
+        }
+        Some(handshake)
+    }
+}
@@ -2,6 +2,11 @@ use crypto;
 use crypto::digest::Digest;
 
 pub type Sha1 = Vec<u8>;
+pub type Sha256 = Vec<u8>;
+
+/// Leaf size of the v2 merkle trees (BEP 52)
+pub const MERKLE_BLOCK_LEN: usize = 0x4000;
+pub const SHA256_LEN: usize = 32;
 
 pub fn sha1(input: &[u8]) -> Sha1 {
     let mut hasher = crypto::sha1::Sha1::new();
@@ -10,3 +15,116 @@ pub fn sha1(input: &[u8]) -> Sha1 {
     hasher.result(&mut hash);
     return hash;
 }
+
+pub fn sha256(input: &[u8]) -> Sha256 {
+    let mut hasher = crypto::sha2::Sha256::new();
+    hasher.input(input);
+    let mut hash: Vec<u8> = vec![0; hasher.output_bytes()];
+    hasher.result(&mut hash);
+    return hash;
+}
+
+/// returns SHA-256 of every 16 KiB block of the data, the last block may be shorter
+pub fn merkle_leaves(data: &[u8]) -> Vec<Sha256> {
+    data.chunks(MERKLE_BLOCK_LEN).map(sha256).collect()
+}
+
+/// returns root of the tree over the hashes padded to the width, which shall be a power of two;
+/// padding hashes are the roots of subtrees with zero leaves
+pub fn merkle_root(hashes: &[Sha256], width: usize) -> Sha256 {
+    let mut layer = hashes.to_vec();
+    let mut width = width;
+    let mut pad = vec![0u8; SHA256_LEN];
+    while width > 1 {
+        if 1 == layer.len() % 2 {
+            layer.push(pad.clone());
+        }
+        layer = layer.chunks(2).map(|pair| hash_pair(&pair[0], &pair[1])).collect();
+        pad = hash_pair(&pad, &pad);
+        width /= 2;
+    }
+    match layer.pop() {
+        Some(root) => root,
+        None => pad,
+    }
+}
+
+/// returns hashes of the layer where every node covers piece_length bytes of the file
+pub fn piece_layer(leaves: &[Sha256], piece_length: usize) -> Vec<Sha256> {
+    let width = piece_length / MERKLE_BLOCK_LEN;
+    leaves.chunks(width).map(|chunk| merkle_root(chunk, width)).collect()
+}
+
+/// returns 'pieces root' of the file with the given leaves
+pub fn file_root(leaves: &[Sha256]) -> Sha256 {
+    merkle_root(leaves, leaves.len().next_power_of_two())
+}
+
+fn hash_pair(left: &[u8], right: &[u8]) -> Sha256 {
+    let mut hasher = crypto::sha2::Sha256::new();
+    hasher.input(left);
+    hasher.input(right);
+    let mut hash: Vec<u8> = vec![0; hasher.output_bytes()];
+    hasher.result(&mut hash);
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustc_serialize::hex::ToHex;
+
+    /// file of the given number of blocks, block N is filled with N + 1 and the last one
+    /// is only last_len bytes long
+    fn file(blocks: usize, last_len: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        for index in 0..blocks - 1 {
+            data.extend(vec![index as u8 + 1; MERKLE_BLOCK_LEN]);
+        }
+        data.extend(vec![blocks as u8; last_len]);
+        data
+    }
+
+    #[test]
+    fn single_block_file() {
+        let leaves = merkle_leaves(b"hello");
+        assert_eq!(1, leaves.len());
+        assert_eq!(
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+            file_root(&leaves).to_hex()
+        );
+    }
+
+    #[test]
+    fn partial_last_piece() {
+        let leaves = merkle_leaves(&file(5, 100));
+        assert_eq!(5, leaves.len());
+        let layer = piece_layer(&leaves, 4 * MERKLE_BLOCK_LEN);
+        assert_eq!(
+            vec![
+                "8aa46b99f2907a2e06a12eed5ab6fcd5bd86bc7f49fe062eeaa78a4304b314eb",
+                "9e6485a4a73bbcf6aee8b224c1096114cefc2ddcc8ec5d37583c2920be0e92e3",
+            ],
+            layer.iter().map(|hash| hash.to_hex()).collect::<Vec<_>>()
+        );
+        // the last piece covers one leaf and three zero hashes
+        let zero = vec![0u8; SHA256_LEN];
+        let padded = [leaves[4].clone(), zero.clone(), zero.clone(), zero];
+        assert_eq!(layer[1], merkle_root(&padded, 4));
+        let root = file_root(&leaves);
+        assert_eq!(
+            "f0e4edeff2eaa82829123cb3436c38b30e83883ce169f017122224d3d4f4af62",
+            root.to_hex()
+        );
+        assert_eq!(root, merkle_root(&layer, 2));
+    }
+
+    #[test]
+    fn empty_file() {
+        // empty files have no 'pieces root', the tree has no leaves
+        let leaves = merkle_leaves(b"");
+        assert!(leaves.is_empty());
+        assert!(piece_layer(&leaves, 2 * MERKLE_BLOCK_LEN).is_empty());
+        assert_eq!(vec![0u8; SHA256_LEN], file_root(&leaves));
+    }
+}
@@ -11,53 +11,114 @@ extern crate tokio_proto;
 extern crate tokio_service;
 extern crate rustc_serialize;
 extern crate byteorder;
+extern crate rand;
+extern crate libc;
 
 pub mod hash;
+pub mod bencode;
+pub mod metainfo;
+pub mod magnet;
+pub mod create;
+pub mod storage;
+#[cfg(unix)]
+pub mod mmap;
+pub mod pieces;
+pub mod resume;
+pub mod recheck;
+pub mod window;
+mod parallel;
 mod codec;
 mod proto;
 mod client;
+mod session;
 mod validate;
 mod echo_server;
+mod reserved;
+mod error;
+mod dh;
+pub mod mse;
+pub mod extension;
+pub mod metadata;
+pub mod utp;
+pub mod transport;
+pub mod listener;
 
 pub use codec::PeerCodec;
 pub use proto::PeerProto;
 pub use validate::Validate;
-pub use client::Client;
+pub use client::{Client, PeerState};
+pub use session::{PeerSession, Timeouts};
 pub use echo_server::Echo;
+pub use reserved::Reserved;
+pub use error::PeerError;
+pub use mse::{Encryption, MseStream};
+pub use extension::ExtendedHandshake;
+pub use utp::{UtpSocket, UtpStream};
+pub use transport::{Transport, PeerStream};
+pub use listener::Listener;
+pub use metainfo::Metainfo;
+pub use magnet::MagnetLink;
+pub use create::TorrentBuilder;
+pub use storage::{Storage, FileStorage};
+#[cfg(unix)]
+pub use mmap::{MmapStorage, Msync};
+pub use pieces::Pieces;
+pub use resume::ResumeData;
+pub use recheck::{Recheck, RecheckEvent};
+pub use window::RequestWindow;
 
 use std::fmt;
 use std::collections::LinkedList;
 use rustc_serialize::hex::ToHex;
+use bytes::Bytes;
 
-pub type Messages = LinkedList<Option<Message>>;
+pub type Messages = LinkedList<Result<Message, PeerError>>;
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum Message {
-    Handshake(Vec<u8>, Vec<u8>),
+    Handshake(Reserved, Vec<u8>, Vec<u8>),
+    InfoHash(Reserved, Vec<u8>),
+    PeerId(Vec<u8>),
     KeepAlive(),
     Choke(),
     Unchoke(),
     Interested(),
     NotInterested(),
     Have(u32),
-    Bitfield(Vec<u8>),
+    Bitfield(Bytes),
     Request(u32, u32, u32),
-    Piece(u32, u32, Vec<u8>),
+    Piece(u32, u32, Bytes),
     Cancel(u32, u32, u32),
     Port(u16),
+    SuggestPiece(u32),
+    HaveAll(),
+    HaveNone(),
+    RejectRequest(u32, u32, u32),
+    AllowedFast(u32),
+    Extended(u8, Vec<u8>),
+    HashRequest(Vec<u8>, u32, u32, u32, u32),
+    Hashes(Vec<u8>, u32, u32, u32, u32, Bytes),
+    HashReject(Vec<u8>, u32, u32, u32, u32),
 }
 
 impl fmt::Display for Message {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match self {
-            &Message::Handshake(ref info, ref id) => {
+            &Message::Handshake(ref reserved, ref info, ref id) => {
                 write!(
                     fmt,
-                    "Handshake([{}][{}])",
+                    "Handshake([{}][{}][{}])",
+                    reserved,
                     info.to_hex(),
                     String::from_utf8_lossy(&id)
                 )?;
             }
+            &Message::InfoHash(ref reserved, ref info) => {
+                write!(fmt, "InfoHash([{}][{}])", reserved, info.to_hex())?;
+            }
+            &Message::PeerId(ref id) => {
+                write!(fmt, "PeerId([{}])", String::from_utf8_lossy(&id))?;
+            }
             &Message::KeepAlive() => {
                 write!(fmt, "KeepAlive()")?;
             }
@@ -91,6 +152,58 @@ impl fmt::Display for Message {
             &Message::Port(ref port) => {
                 write!(fmt, "Port({})", port)?;
             }
+            &Message::SuggestPiece(ref index) => {
+                write!(fmt, "SuggestPiece({})", index)?;
+            }
+            &Message::HaveAll() => {
+                write!(fmt, "HaveAll()")?;
+            }
+            &Message::HaveNone() => {
+                write!(fmt, "HaveNone()")?;
+            }
+            &Message::RejectRequest(ref index, ref offset, ref length) => {
+                write!(fmt, "RejectRequest({}, {}, {})", index, offset, length)?;
+            }
+            &Message::AllowedFast(ref index) => {
+                write!(fmt, "AllowedFast({})", index)?;
+            }
+            &Message::Extended(ref id, ref payload) => {
+                write!(fmt, "Extended({}, [u8; {}])", id, payload.len())?;
+            }
+            &Message::HashRequest(ref root, ref base, ref index, ref length, ref proof) => {
+                write!(
+                    fmt,
+                    "HashRequest([{}], {}, {}, {}, {})",
+                    root.to_hex(),
+                    base,
+                    index,
+                    length,
+                    proof
+                )?;
+            }
+            &Message::Hashes(ref root, ref base, ref index, ref length, ref proof, ref hashes) => {
+                write!(
+                    fmt,
+                    "Hashes([{}], {}, {}, {}, {}, [u8; {}])",
+                    root.to_hex(),
+                    base,
+                    index,
+                    length,
+                    proof,
+                    hashes.len()
+                )?;
+            }
+            &Message::HashReject(ref root, ref base, ref index, ref length, ref proof) => {
+                write!(
+                    fmt,
+                    "HashReject([{}], {}, {}, {}, {})",
+                    root.to_hex(),
+                    base,
+                    index,
+                    length,
+                    proof
+                )?;
+            }
         };
         write!(fmt, "")
     }
@@ -0,0 +1,230 @@
+use std::io;
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::net::SocketAddr;
+use std::collections::HashMap;
+
+use futures::{future, Future, Stream};
+use futures::future::Either;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::AsyncRead;
+
+use Client;
+use PeerState;
+use PeerCodec;
+use PeerStream;
+use PeerError;
+use Message;
+use Encryption;
+use Storage;
+use Pieces;
+use Metainfo;
+use Timeouts;
+use codec;
+use mse;
+
+const MAX_CONNECTIONS: usize = 50;
+
+pub type ClientStream = Box<Stream<Item = Client, Error = io::Error>>;
+
+/// Torrent which is served to the incoming peers
+struct Torrent {
+    info_hash: Vec<u8>,
+    /// v2 support is announced only for v2 and hybrid torrents
+    v2: bool,
+    storage: Rc<RefCell<Storage>>,
+    pieces: Rc<RefCell<Pieces>>,
+}
+
+/// Accepts incoming peers of the served torrents: the peer's handshake selects the torrent,
+/// then it's answered by our handshake and bitfield and the connection is driven by PeerSession
+pub struct Listener {
+    listener: TcpListener,
+    handle: Handle,
+    id: Vec<u8>,
+    encryption: Encryption,
+    timeouts: Timeouts,
+    max_connections: usize,
+    /// torrents by the info hash as it's sent in the handshake
+    torrents: HashMap<Vec<u8>, Torrent>,
+}
+
+impl Listener {
+    pub fn bind(addr: &SocketAddr, handle: &Handle, id: &[u8]) -> io::Result<Self> {
+        Ok(Listener {
+            listener: TcpListener::bind(addr, handle)?,
+            handle: handle.clone(),
+            id: Vec::from(id),
+            encryption: Encryption::Enabled,
+            timeouts: Timeouts::new(),
+            max_connections: MAX_CONNECTIONS,
+            torrents: HashMap::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// both encrypted and plaintext peers are accepted by default
+    pub fn set_encryption(&mut self, encryption: Encryption) {
+        self.encryption = encryption;
+    }
+
+    /// timers of the accepted connections, the handshake timeout applies to the whole
+    /// handshake including the stream encryption
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    /// peers beyond the limit are disconnected right after they are accepted
+    pub fn set_max_connections(&mut self, connections: usize) {
+        self.max_connections = connections;
+    }
+
+    /// serves the torrent, blocks are read from the storage if they are verified in pieces
+    pub fn add_torrent(
+        &mut self,
+        metainfo: &Metainfo,
+        storage: Rc<RefCell<Storage>>,
+        pieces: Rc<RefCell<Pieces>>,
+    ) {
+        let info_hash = &metainfo.info_hash;
+        let torrent = Torrent {
+            info_hash: info_hash.clone(),
+            v2: metainfo.info_hash_v2.is_some(),
+            storage: storage,
+            pieces: pieces,
+        };
+        self.torrents.insert(Vec::from(codec::handshake_hash(info_hash)), torrent);
+    }
+
+    /// returns stream of the peers which have completed the handshake, peers which have
+    /// failed it are dropped; the connection is counted until it's closed
+    pub fn incoming(self) -> ClientStream {
+        let handle = self.handle;
+        let id = Rc::new(self.id);
+        let encryption = self.encryption;
+        let timeouts = self.timeouts;
+        let max_connections = self.max_connections;
+        let torrents = Rc::new(self.torrents);
+        let connections = Rc::new(Cell::new(0));
+        let clients = self.listener
+            .incoming()
+            .filter_map(move |(stream, addr)| {
+                if connections.get() >= max_connections {
+                    println!("Listener::incoming() connection limit, {} is dropped", addr);
+                    return None;
+                }
+                let slot = Slot::new(connections.clone());
+                let accept = accept(
+                    stream,
+                    addr,
+                    &handle,
+                    id.clone(),
+                    torrents.clone(),
+                    encryption,
+                    timeouts,
+                    slot,
+                );
+                Some(accept.then(move |result| match result {
+                    Ok(client) => Ok(Some(client)),
+                    Err(err) => {
+                        println!("Listener::incoming() peer {} is rejected: {}", addr, err);
+                        Ok(None)
+                    }
+                }))
+            })
+            .buffer_unordered(max_connections)
+            .filter_map(|client| client);
+        Box::new(clients)
+    }
+}
+
+/// Counts the connection until it's closed
+pub struct Slot {
+    connections: Rc<Cell<usize>>,
+}
+
+impl Slot {
+    fn new(connections: Rc<Cell<usize>>) -> Self {
+        connections.set(connections.get() + 1);
+        Slot { connections: connections }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.connections.set(self.connections.get() - 1);
+    }
+}
+
+/// performs the handshake of the incoming peer and spawns its session
+fn accept(
+    stream: TcpStream,
+    addr: SocketAddr,
+    handle: &Handle,
+    id: Rc<Vec<u8>>,
+    torrents: Rc<HashMap<Vec<u8>, Torrent>>,
+    encryption: Encryption,
+    timeouts: Timeouts,
+    slot: Slot,
+) -> Box<Future<Item = Client, Error = PeerError>> {
+    let hashes = torrents.keys().cloned().collect();
+    let session_handle = handle.clone();
+    let handshake = mse::accept(PeerStream::Tcp(stream), hashes, encryption)
+        .and_then(|(stream, skey)| {
+            let mut codec = PeerCodec::new();
+            codec.set_split_handshake(true);
+            stream.framed(codec).into_future().map_err(|(err, _)| PeerError::from(err)).map(
+                move |(messages, transport)| (messages, transport, skey),
+            )
+        })
+        .and_then(move |(messages, transport, skey)| {
+            let mut messages = messages.unwrap_or_default();
+            let info_hash = match messages.front() {
+                Some(&Ok(Message::InfoHash(_, ref info_hash))) => info_hash.clone(),
+                _ => return Err(PeerError::BadHandshake("handshake is expected")),
+            };
+            if skey.map_or(false, |skey| skey != info_hash) {
+                return Err(PeerError::BadHandshake("info hash differs from the encryption key"));
+            }
+            let torrent = match torrents.get(&info_hash) {
+                Some(torrent) => torrent,
+                None => return Err(PeerError::UnknownInfoHash(info_hash)),
+            };
+            let mut state = PeerState::new(addr, &torrent.info_hash);
+            state.set_storage(torrent.storage.clone());
+            state.set_pieces(torrent.pieces.clone());
+            state.timeouts = timeouts;
+            state.slot = Some(slot);
+            state.reserved.set_v2(torrent.v2);
+            // the handshake gives the peer's reserved bits, the rest of the batch is processed
+            // after ours is queued, since its answers can't precede our handshake
+            if let Some(handshake) = messages.pop_front() {
+                state.messages.push_back(handshake);
+            }
+            state.dispatch()?;
+            let info_hash = torrent.info_hash.clone();
+            let reserved = state.reserved;
+            state.send(Message::Handshake(reserved, info_hash, (*id).clone()));
+            if let Some(have) = state.have_message() {
+                state.send(have);
+            }
+            state.messages.append(&mut messages);
+            state.dispatch()?;
+            Client::spawn(&session_handle, transport, state).map_err(PeerError::from)
+        });
+    match Timeout::new(timeouts.handshake, handle) {
+        Ok(timeout) => {
+            Box::new(handshake.select2(timeout).then(|result| match result {
+                Ok(Either::A((client, _))) => Ok(client),
+                Ok(Either::B(_)) => Err(PeerError::Timeout("peer hasn't sent the handshake")),
+                Err(Either::A((err, _))) => Err(err),
+                Err(Either::B((err, _))) => Err(PeerError::from(err)),
+            }))
+        }
+        Err(err) => Box::new(future::err(PeerError::from(err))),
+    }
+}
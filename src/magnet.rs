@@ -0,0 +1,381 @@
+use std::fmt;
+use std::error;
+use std::str;
+use std::net::SocketAddr;
+use rustc_serialize::hex::{FromHex, ToHex};
+
+use Metainfo;
+
+const SCHEME: &'static str = "magnet:?";
+const BTIH: &'static str = "urn:btih:";
+const BTMH: &'static str = "urn:btmh:";
+/// multihash prefix of SHA-256: <code=0x12><length=0x20>
+const SHA256_MULTIHASH: &'static str = "1220";
+const BTIH_LEN: usize = 20;
+const BTMH_LEN: usize = 32;
+const BASE32: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Errors of the magnet URI parser
+#[derive(PartialEq, Debug, Clone)]
+pub enum MagnetError {
+    /// URI doesn't start with 'magnet:?'
+    NotMagnet,
+    /// neither btih nor btmh exact topic is present
+    MissingHash,
+    Invalid(&'static str),
+}
+
+impl fmt::Display for MagnetError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &MagnetError::NotMagnet => write!(fmt, "Not a magnet URI"),
+            &MagnetError::MissingHash => write!(fmt, "Magnet URI has no info hash"),
+            &MagnetError::Invalid(ref reason) => write!(fmt, "Invalid magnet URI: {}", reason),
+        }
+    }
+}
+
+impl error::Error for MagnetError {
+    fn description(&self) -> &str {
+        match self {
+            &MagnetError::NotMagnet => "not a magnet URI",
+            &MagnetError::MissingHash => "missing info hash",
+            &MagnetError::Invalid(_) => "invalid magnet URI",
+        }
+    }
+}
+
+/// Magnet URI of a torrent (BEP 9, BEP 52)
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct MagnetLink {
+    /// SHA-1 info hash, xt=urn:btih: in hex or base32
+    pub btih: Option<Vec<u8>>,
+    /// SHA-256 info hash of v2 torrents, xt=urn:btmh: multihash
+    pub btmh: Option<Vec<u8>>,
+    /// display name, dn
+    pub name: Option<String>,
+    /// tracker URLs, tr
+    pub trackers: Vec<String>,
+    /// peer addresses, x.pe
+    pub peers: Vec<SocketAddr>,
+    /// web seed URLs, ws
+    pub web_seeds: Vec<String>,
+    /// inclusive ranges of selected file indices, so
+    pub select_only: Vec<(u32, u32)>,
+}
+
+impl MagnetLink {
+    pub fn parse(uri: &str) -> Result<Self, MagnetError> {
+        if !uri.starts_with(SCHEME) {
+            return Err(MagnetError::NotMagnet);
+        }
+        let mut link = MagnetLink::default();
+        for pair in uri[SCHEME.len()..].split('&') {
+            let mut pair = pair.splitn(2, '=');
+            let key = pair.next().unwrap_or("");
+            let value = percent_decode(pair.next().unwrap_or(""))?;
+            match key {
+                "xt" => {
+                    if let Some(hash) = strip_urn(&value, BTIH) {
+                        link.btih = Some(parse_btih(hash)?);
+                    } else if let Some(hash) = strip_urn(&value, BTMH) {
+                        link.btmh = Some(parse_btmh(hash)?);
+                    }
+                }
+                "dn" => link.name = Some(value),
+                "tr" => link.trackers.push(value),
+                "ws" => link.web_seeds.push(value),
+                "x.pe" => {
+                    // host names would need a resolver, only literal addresses are kept
+                    if let Ok(addr) = value.parse() {
+                        link.peers.push(addr);
+                    }
+                }
+                "so" => link.select_only = parse_select_only(&value)?,
+                _ => {
+                    // unknown parameters are ignored
+                }
+            }
+        }
+        if link.btih.is_none() && link.btmh.is_none() {
+            return Err(MagnetError::MissingHash);
+        }
+        Ok(link)
+    }
+
+    /// returns magnet of the torrent with the name and all trackers of the metainfo
+    pub fn from_metainfo(metainfo: &Metainfo) -> Self {
+        let mut link = MagnetLink::default();
+        // SHA-1 of the v2 only info dictionary isn't a v1 info hash
+        if metainfo.has_v1() {
+            link.btih = Some(metainfo.info_hash.clone());
+        }
+        link.btmh = metainfo.info_hash_v2.clone();
+        link.name = Some(metainfo.name.clone());
+        let tiers = metainfo.announce_list.iter().flat_map(|tier| tier.iter());
+        for tracker in metainfo.announce.iter().chain(tiers) {
+            if !link.trackers.contains(tracker) {
+                link.trackers.push(tracker.clone());
+            }
+        }
+        link
+    }
+
+    /// returns the hash for the handshake, v1 hash is preferred
+    pub fn info_hash(&self) -> &[u8] {
+        match (&self.btih, &self.btmh) {
+            (&Some(ref hash), _) |
+            (&None, &Some(ref hash)) => hash,
+            (&None, &None) => &[],
+        }
+    }
+
+    /// returns true if the file shall be downloaded
+    pub fn is_selected(&self, index: u32) -> bool {
+        self.select_only.is_empty() ||
+            self.select_only.iter().any(
+                |&(first, last)| first <= index && index <= last,
+            )
+    }
+}
+
+impl fmt::Display for MagnetLink {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let mut params = Vec::new();
+        if let Some(ref hash) = self.btih {
+            params.push(format!("xt={}{}", BTIH, hash.to_hex()));
+        }
+        if let Some(ref hash) = self.btmh {
+            params.push(format!("xt={}{}{}", BTMH, SHA256_MULTIHASH, hash.to_hex()));
+        }
+        if let Some(ref name) = self.name {
+            params.push(format!("dn={}", percent_encode(name)));
+        }
+        for tracker in &self.trackers {
+            params.push(format!("tr={}", percent_encode(tracker)));
+        }
+        for peer in &self.peers {
+            params.push(format!("x.pe={}", percent_encode(&peer.to_string())));
+        }
+        for seed in &self.web_seeds {
+            params.push(format!("ws={}", percent_encode(seed)));
+        }
+        if !self.select_only.is_empty() {
+            let ranges = self.select_only
+                .iter()
+                .map(|&(first, last)| if first == last {
+                    format!("{}", first)
+                } else {
+                    format!("{}-{}", first, last)
+                })
+                .collect::<Vec<_>>();
+            params.push(format!("so={}", ranges.join(",")));
+        }
+        write!(fmt, "{}{}", SCHEME, params.join("&"))
+    }
+}
+
+/// returns the value after the URN prefix, the prefix is case-insensitive
+fn strip_urn<'a>(value: &'a str, prefix: &str) -> Option<&'a str> {
+    match value.get(..prefix.len()) {
+        Some(head) if head.eq_ignore_ascii_case(prefix) => Some(&value[prefix.len()..]),
+        _ => None,
+    }
+}
+
+fn parse_btih(hash: &str) -> Result<Vec<u8>, MagnetError> {
+    let hash = match hash.len() {
+        40 => hash.from_hex().map_err(|_| MagnetError::Invalid("bad hex in btih"))?,
+        32 => base32_decode(hash).ok_or(MagnetError::Invalid("bad base32 in btih"))?,
+        _ => return Err(MagnetError::Invalid("btih shall be 40 hex or 32 base32 chars")),
+    };
+    debug_assert_eq!(hash.len(), BTIH_LEN);
+    Ok(hash)
+}
+
+fn parse_btmh(hash: &str) -> Result<Vec<u8>, MagnetError> {
+    if !hash.starts_with(SHA256_MULTIHASH) {
+        return Err(MagnetError::Invalid("btmh shall be a SHA-256 multihash"));
+    }
+    let hash = hash[SHA256_MULTIHASH.len()..].from_hex().map_err(|_| {
+        MagnetError::Invalid("bad hex in btmh")
+    })?;
+    if hash.len() != BTMH_LEN {
+        return Err(MagnetError::Invalid("btmh digest shall be 32 bytes"));
+    }
+    Ok(hash)
+}
+
+/// parses list like '0,2,4,6-8'
+fn parse_select_only(value: &str) -> Result<Vec<(u32, u32)>, MagnetError> {
+    let mut ranges = Vec::new();
+    for item in value.split(',') {
+        let mut bounds = item.splitn(2, '-');
+        let first = bounds.next().unwrap_or("").parse::<u32>();
+        let last = bounds.next().map_or(first.clone(), |last| last.parse::<u32>());
+        match (first, last) {
+            (Ok(first), Ok(last)) if first <= last => ranges.push((first, last)),
+            _ => return Err(MagnetError::Invalid("bad file range in so")),
+        }
+    }
+    Ok(ranges)
+}
+
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for symbol in text.to_uppercase().bytes() {
+        let value = BASE32.iter().position(|c| *c == symbol)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+fn percent_decode(value: &str) -> Result<String, MagnetError> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut iter = value.bytes();
+    while let Some(byte) = iter.next() {
+        match byte {
+            b'%' => {
+                let hex = [
+                    iter.next().unwrap_or(b' '),
+                    iter.next().unwrap_or(b' '),
+                ];
+                // from_str_radix takes a sign, so the digits are checked first
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return Err(MagnetError::Invalid("bad percent escape"));
+                }
+                let decoded = str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or(MagnetError::Invalid("bad percent escape"))?;
+                bytes.push(decoded);
+            }
+            b'+' => bytes.push(b' '),
+            _ => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| MagnetError::Invalid("value is not UTF-8"))
+}
+
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &'static str = "000102030405060708090a0b0c0d0e0f10111213";
+
+    fn hash() -> Vec<u8> {
+        (0..20).collect()
+    }
+
+    #[test]
+    fn hex_btih() {
+        let link = MagnetLink::parse(&format!("magnet:?xt=urn:btih:{}", HEX)).unwrap();
+        assert_eq!(Some(hash()), link.btih);
+        let link = MagnetLink::parse(&format!("magnet:?xt=URN:BTIH:{}", HEX.to_uppercase()))
+            .unwrap();
+        assert_eq!(Some(hash()), link.btih);
+    }
+
+    #[test]
+    fn base32_btih() {
+        let link = MagnetLink::parse("magnet:?xt=urn:btih:AAAQEAYEAUDAOCAJBIFQYDIOB4IBCEQT")
+            .unwrap();
+        assert_eq!(Some(hash()), link.btih);
+        let link = MagnetLink::parse("magnet:?xt=urn:btih:aaaqeayeaudaocajbifqydiob4ibceqt")
+            .unwrap();
+        assert_eq!(Some(hash()), link.btih);
+        assert_eq!(
+            Err(MagnetError::Invalid("bad base32 in btih")),
+            MagnetLink::parse("magnet:?xt=urn:btih:AAAQEAYEAUDAOCAJBIFQYDIOB4IBCEQ1")
+        );
+    }
+
+    #[test]
+    fn btmh() {
+        let digest = vec![0xab; 32];
+        let uri = format!("magnet:?xt=urn:btmh:1220{}", digest.to_hex());
+        let link = MagnetLink::parse(&uri).unwrap();
+        assert_eq!(Some(digest.clone()), link.btmh);
+        assert_eq!(None, link.btih);
+        assert_eq!(&digest[..], link.info_hash());
+        let uri = format!("magnet:?xt=Urn:BtMh:1220{}", digest.to_hex());
+        assert_eq!(Some(digest), MagnetLink::parse(&uri).unwrap().btmh);
+        assert_eq!(
+            Err(MagnetError::Invalid("btmh shall be a SHA-256 multihash")),
+            MagnetLink::parse(&format!("magnet:?xt=urn:btmh:1114{}", HEX))
+        );
+        assert_eq!(
+            Err(MagnetError::Invalid("btmh digest shall be 32 bytes")),
+            MagnetLink::parse(&format!("magnet:?xt=urn:btmh:1220{}", HEX))
+        );
+    }
+
+    #[test]
+    fn select_only() {
+        let uri = format!("magnet:?xt=urn:btih:{}&so=0,2,4-6", HEX);
+        let link = MagnetLink::parse(&uri).unwrap();
+        assert_eq!(vec![(0, 0), (2, 2), (4, 6)], link.select_only);
+        assert!(link.is_selected(5));
+        assert!(!link.is_selected(3));
+        for so in &["6-4", "1,", "a", "-1"] {
+            let uri = format!("magnet:?xt=urn:btih:{}&so={}", HEX, so);
+            assert_eq!(
+                Err(MagnetError::Invalid("bad file range in so")),
+                MagnetLink::parse(&uri)
+            );
+        }
+    }
+
+    #[test]
+    fn bad_escapes() {
+        for name in &["%+1", "%-1", "%1", "%", "%zz", "%1g"] {
+            let uri = format!("magnet:?xt=urn:btih:{}&dn={}", HEX, name);
+            assert_eq!(
+                Err(MagnetError::Invalid("bad percent escape")),
+                MagnetLink::parse(&uri)
+            );
+        }
+        let uri = format!("magnet:?xt=urn:btih:{}&dn=a%20b+c%2B", HEX);
+        assert_eq!(Some("a b c+".to_string()), MagnetLink::parse(&uri).unwrap().name);
+    }
+
+    #[test]
+    fn missing_hash() {
+        assert_eq!(Err(MagnetError::NotMagnet), MagnetLink::parse("http://example.com"));
+        assert_eq!(Err(MagnetError::MissingHash), MagnetLink::parse("magnet:?dn=name"));
+    }
+
+    #[test]
+    fn round_trip() {
+        let link = MagnetLink {
+            btih: Some(hash()),
+            btmh: Some(vec![0xcd; 32]),
+            name: Some("name with spaces & symbols".to_string()),
+            trackers: vec!["udp://tracker.example:80/announce?a=1&b=2".to_string()],
+            peers: vec!["10.0.0.1:6881".parse().unwrap(), "[::1]:6882".parse().unwrap()],
+            web_seeds: vec!["http://seed.example/file".to_string()],
+            select_only: vec![(1, 1), (3, 5)],
+        };
+        assert_eq!(Ok(link.clone()), MagnetLink::parse(&link.to_string()));
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::BTreeMap;
+
+use bencode::{Decoder, Value};
+use hash::{sha1, sha256, SHA256_LEN};
+
+/// Extension name of the metadata exchange (BEP 9)
+pub const EXTENSION_NAME: &'static str = "ut_metadata";
+/// Extended message id the peers shall use to send ut_metadata messages to us
+pub const LOCAL_ID: u8 = 2;
+/// Metadata is transferred in pieces of 16 KiB, the last one may be shorter
+pub const PIECE_LEN: usize = 0x4000;
+/// Larger info dictionaries are refused, nobody needs them and they would eat the memory
+pub const MAX_METADATA_SIZE: usize = 0x800000;
+
+/// Metadata isn't requested anymore after this number of hash check failures
+pub const MAX_FAILURES: u32 = 3;
+
+const REQUEST: i64 = 0;
+const DATA: i64 = 1;
+const REJECT: i64 = 2;
+
+/// Message of the metadata exchange, carried as the extended message payload
+#[derive(PartialEq, Debug, Clone)]
+pub enum MetadataMessage {
+    Request(u32),
+    /// piece index, total size of the metadata, piece data
+    Data(u32, u32, Vec<u8>),
+    Reject(u32),
+}
+
+impl MetadataMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        // <bencoded dictionary><piece data, data message only>
+        let mut dict = BTreeMap::new();
+        let (msg_type, piece) = match self {
+            &MetadataMessage::Request(piece) => (REQUEST, piece),
+            &MetadataMessage::Data(piece, total_size, _) => {
+                dict.insert(Vec::from(&b"total_size"[..]), Value::Int(total_size as i64));
+                (DATA, piece)
+            }
+            &MetadataMessage::Reject(piece) => (REJECT, piece),
+        };
+        dict.insert(Vec::from(&b"msg_type"[..]), Value::Int(msg_type));
+        dict.insert(Vec::from(&b"piece"[..]), Value::Int(piece as i64));
+        let mut buf = Value::Dict(dict).encode();
+        if let &MetadataMessage::Data(_, _, ref data) = self {
+            buf.extend_from_slice(data);
+        }
+        buf
+    }
+
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        let mut decoder = Decoder::new(payload);
+        decoder.set_strict(false);
+        let dict = decoder.next_value().ok()??;
+        let piece = dict.get("piece").and_then(Value::as_int)?;
+        if piece < 0 || piece > u32::max_value() as i64 {
+            return None;
+        }
+        let piece = piece as u32;
+        match dict.get("msg_type").and_then(Value::as_int)? {
+            REQUEST => Some(MetadataMessage::Request(piece)),
+            DATA => {
+                let size = dict.get("total_size").and_then(Value::as_int)?;
+                if size < 0 || size > u32::max_value() as i64 {
+                    return None;
+                }
+                let data = Vec::from(decoder.remaining());
+                Some(MetadataMessage::Data(piece, size as u32, data))
+            }
+            REJECT => Some(MetadataMessage::Reject(piece)),
+            _ => None,
+        }
+    }
+}
+
+/// returns number of the pieces the metadata of the given size is split into
+pub fn piece_count(size: usize) -> u32 {
+    ((size + PIECE_LEN - 1) / PIECE_LEN) as u32
+}
+
+/// returns the piece of the metadata or None if the index is out of range
+pub fn piece(metadata: &[u8], index: u32) -> Option<&[u8]> {
+    let start = index as usize * PIECE_LEN;
+    if start >= metadata.len() {
+        return None;
+    }
+    let end = if metadata.len() - start < PIECE_LEN {
+        metadata.len()
+    } else {
+        start + PIECE_LEN
+    };
+    Some(&metadata[start..end])
+}
+
+/// Collects metadata pieces received from the peer
+pub struct MetadataBuffer {
+    info_hash: Vec<u8>,
+    size: usize,
+    pieces: Vec<Option<Vec<u8>>>,
+    requested: Vec<bool>,
+    /// number of the assembled metadata which hasn't matched the info hash
+    failures: u32,
+}
+
+impl MetadataBuffer {
+    /// returns None if the announced size is unusable
+    pub fn new(info_hash: &[u8], size: usize) -> Option<Self> {
+        if 0 == size || size > MAX_METADATA_SIZE {
+            return None;
+        }
+        let count = piece_count(size) as usize;
+        Some(MetadataBuffer {
+            info_hash: Vec::from(info_hash),
+            size: size,
+            pieces: vec![None; count],
+            requested: vec![false; count],
+            failures: 0,
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// returns index of the piece which is neither received nor requested and marks it requested,
+    /// nothing is requested after too many failures
+    pub fn next_request(&mut self) -> Option<u32> {
+        if self.has_failed() {
+            return None;
+        }
+        let index = (0..self.pieces.len()).find(|&index| {
+            self.pieces[index].is_none() && !self.requested[index]
+        })?;
+        self.requested[index] = true;
+        Some(index as u32)
+    }
+
+    /// peer has refused the piece, it may be requested again
+    pub fn reject(&mut self, index: u32) {
+        if let Some(requested) = self.requested.get_mut(index as usize) {
+            *requested = false;
+        }
+    }
+
+    /// stores the piece, returns false if it doesn't fit the metadata geometry
+    pub fn insert(&mut self, index: u32, total_size: u32, data: Vec<u8>) -> bool {
+        let index = index as usize;
+        if total_size as usize != self.size || index >= self.pieces.len() {
+            return false;
+        }
+        let expected = if index + 1 == self.pieces.len() {
+            self.size - index * PIECE_LEN
+        } else {
+            PIECE_LEN
+        };
+        if data.len() != expected {
+            return false;
+        }
+        self.pieces[index] = Some(data);
+        self.requested[index] = false;
+        true
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pieces.iter().all(Option::is_some)
+    }
+
+    /// returns true if the peer has sent wrong metadata too many times
+    pub fn has_failed(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+
+    /// returns assembled info dictionary if its hash matches the info hash, SHA-256 is used
+    /// for v2 info hashes; otherwise all pieces are dropped to be downloaded again
+    pub fn verify(&mut self) -> Option<Vec<u8>> {
+        if !self.is_complete() {
+            return None;
+        }
+        let mut metadata = Vec::with_capacity(self.size);
+        for piece in &self.pieces {
+            metadata.extend_from_slice(piece.as_ref().unwrap());
+        }
+        let hash = if SHA256_LEN == self.info_hash.len() {
+            sha256(&metadata)
+        } else {
+            sha1(&metadata)
+        };
+        if hash == self.info_hash {
+            Some(metadata)
+        } else {
+            self.failures += 1;
+            for piece in self.pieces.iter_mut() {
+                *piece = None;
+            }
+            None
+        }
+    }
+}
@@ -0,0 +1,459 @@
+use std::io;
+use std::fmt;
+use std::error;
+use std::fs::File as FsFile;
+use std::io::Read;
+use std::path::Path;
+use std::collections::BTreeMap;
+
+use bencode::{self, BencodeError, Decoder, Value};
+use hash::{sha1, sha256, Sha1, Sha256, SHA256_LEN};
+
+/// Length of the block requested from peers
+pub const BLOCK_LEN: u32 = 0x4000;
+const PIECE_HASH_LEN: usize = 20;
+
+/// Errors of the .torrent file parser
+#[derive(Debug)]
+pub enum MetainfoError {
+    Bencode(BencodeError),
+    /// mandatory key is absent or has a wrong type
+    Missing(&'static str),
+    /// value is present but unusable
+    Invalid(&'static str),
+    Io(io::Error),
+}
+
+impl fmt::Display for MetainfoError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &MetainfoError::Bencode(ref err) => write!(fmt, "Bencode: {}", err),
+            &MetainfoError::Missing(ref key) => write!(fmt, "Missing key '{}'", key),
+            &MetainfoError::Invalid(ref reason) => write!(fmt, "Invalid metainfo: {}", reason),
+            &MetainfoError::Io(ref err) => write!(fmt, "I/O error: {}", err),
+        }
+    }
+}
+
+impl error::Error for MetainfoError {
+    fn description(&self) -> &str {
+        match self {
+            &MetainfoError::Bencode(_) => "bencode error",
+            &MetainfoError::Missing(_) => "missing key",
+            &MetainfoError::Invalid(_) => "invalid metainfo",
+            &MetainfoError::Io(_) => "I/O error",
+        }
+    }
+}
+
+impl From<BencodeError> for MetainfoError {
+    fn from(err: BencodeError) -> MetainfoError {
+        MetainfoError::Bencode(err)
+    }
+}
+
+impl From<io::Error> for MetainfoError {
+    fn from(err: io::Error) -> MetainfoError {
+        MetainfoError::Io(err)
+    }
+}
+
+/// File of the torrent, path is relative to the torrent name
+#[derive(PartialEq, Debug, Clone)]
+pub struct File {
+    pub length: u64,
+    pub path: Vec<String>,
+    /// padding file (BEP 47), aligns the next file to the piece boundary
+    pub padding: bool,
+    /// root of the file merkle tree (BEP 52), absent for v1 and empty files
+    pub pieces_root: Option<Sha256>,
+}
+
+/// Content of the .torrent file
+#[derive(PartialEq, Debug, Clone)]
+pub struct Metainfo {
+    pub announce: Option<String>,
+    pub announce_list: Vec<Vec<String>>,
+    /// web seed URLs (BEP 19)
+    pub url_list: Vec<String>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    pub creation_date: Option<i64>,
+    /// file name of the single file torrent or the directory name of the multi-file one
+    pub name: String,
+    pub piece_length: u32,
+    pub pieces: Vec<Sha1>,
+    /// single file torrent has one file with path [name]
+    pub files: Vec<File>,
+    pub multi_file: bool,
+    pub private: bool,
+    /// SHA-1 of the bencoded info dictionary exactly as it is in the file
+    pub info_hash: Vec<u8>,
+    /// SHA-256 of the bencoded info dictionary of v2 and hybrid torrents
+    pub info_hash_v2: Option<Vec<u8>>,
+    /// pieces root to the concatenated piece layer hashes of files longer than a piece
+    pub piece_layers: BTreeMap<Sha256, Vec<u8>>,
+    /// bencoded info dictionary
+    pub info: Vec<u8>,
+}
+
+impl Metainfo {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, MetainfoError> {
+        let mut data = Vec::new();
+        FsFile::open(path)?.read_to_end(&mut data)?;
+        Self::from_bytes(&data)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, MetainfoError> {
+        // real world files are not always canonical outside of the info dictionary
+        let mut decoder = Decoder::new(data);
+        decoder.set_strict(false);
+        let dict = decoder.raw_dict()?;
+        let info = *dict.get(&b"info"[..]).ok_or(MetainfoError::Missing("info"))?;
+        let mut metainfo = Self::from_info(info)?;
+
+        let value = |key: &str| dict.get(key.as_bytes()).and_then(|raw| {
+            bencode::decode_lenient(raw).ok()
+        });
+        metainfo.announce = value("announce").and_then(|v| v.as_str().map(String::from));
+        metainfo.comment = value("comment").and_then(|v| v.as_str().map(String::from));
+        metainfo.created_by = value("created by").and_then(|v| v.as_str().map(String::from));
+        metainfo.creation_date = value("creation date").and_then(|v| v.as_int());
+        if let Some(tiers) = value("announce-list") {
+            for tier in tiers.as_list().into_iter().flat_map(|tiers| tiers.iter()) {
+                let tier = tier.as_list()
+                    .into_iter()
+                    .flat_map(|tier| tier.iter())
+                    .filter_map(|url| url.as_str().map(String::from))
+                    .collect::<Vec<_>>();
+                if !tier.is_empty() {
+                    metainfo.announce_list.push(tier);
+                }
+            }
+        }
+        if let Some(raw) = dict.get(&b"piece layers"[..]) {
+            let mut layers = Decoder::new(raw);
+            layers.set_strict(false);
+            for (root, layer) in layers.raw_dict()? {
+                if let Some(Value::Bytes(hashes)) = bencode::decode_lenient(layer).ok() {
+                    if 0 != hashes.len() % SHA256_LEN {
+                        return Err(MetainfoError::Invalid("piece layer is not a multiple of 32"));
+                    }
+                    metainfo.piece_layers.insert(root, hashes);
+                }
+            }
+        }
+        match value("url-list") {
+            Some(Value::Bytes(ref url)) => {
+                metainfo.url_list.extend(String::from_utf8(url.clone()).ok());
+            }
+            Some(Value::List(ref urls)) => {
+                metainfo.url_list = urls.iter()
+                    .filter_map(|url| url.as_str().map(String::from))
+                    .collect();
+            }
+            _ => {}
+        }
+        Ok(metainfo)
+    }
+
+    /// returns content of the .torrent file
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dict = BTreeMap::new();
+        {
+            let mut put = |key: &str, value: Value| {
+                dict.insert(Vec::from(key.as_bytes()), value.encode());
+            };
+            if let Some(ref announce) = self.announce {
+                put("announce", Value::from(announce.as_str()));
+            }
+            if !self.announce_list.is_empty() {
+                put("announce-list", Value::List(self.announce_list.iter().map(|tier| {
+                    Value::List(tier.iter().map(|url| Value::from(url.as_str())).collect())
+                }).collect()));
+            }
+            if let Some(ref comment) = self.comment {
+                put("comment", Value::from(comment.as_str()));
+            }
+            if let Some(ref created_by) = self.created_by {
+                put("created by", Value::from(created_by.as_str()));
+            }
+            if let Some(date) = self.creation_date {
+                put("creation date", Value::Int(date));
+            }
+            if !self.piece_layers.is_empty() {
+                let mut layers = BTreeMap::new();
+                for (root, hashes) in &self.piece_layers {
+                    layers.insert(root.clone(), Value::Bytes(hashes.clone()));
+                }
+                put("piece layers", Value::Dict(layers));
+            }
+            if !self.url_list.is_empty() {
+                put("url-list", Value::List(self.url_list.iter().map(|url| {
+                    Value::from(url.as_str())
+                }).collect()));
+            }
+        }
+        // info is written as is, re-encoding could change the info hash
+        dict.insert(Vec::from(&b"info"[..]), self.info.clone());
+        bencode::encode_raw_dict(&dict)
+    }
+
+    /// parses the bencoded info dictionary, e.g. received from peers by ut_metadata
+    pub fn from_info(info: &[u8]) -> Result<Self, MetainfoError> {
+        let dict = bencode::decode_lenient(info)?;
+        if dict.as_dict().is_none() {
+            return Err(MetainfoError::Missing("info"));
+        }
+        let name = dict.get("name")
+            .and_then(Value::as_str)
+            .ok_or(MetainfoError::Missing("name"))?;
+        check_path_component(name)?;
+        let piece_length = dict.get("piece length")
+            .and_then(Value::as_int)
+            .ok_or(MetainfoError::Missing("piece length"))?;
+        if piece_length <= 0 || piece_length > u32::max_value() as i64 {
+            return Err(MetainfoError::Invalid("piece length is out of range"));
+        }
+        let v2 = dict.get("meta version").and_then(Value::as_int) == Some(2);
+        let mut tree = Vec::new();
+        if v2 {
+            let root = dict.get("file tree").ok_or(MetainfoError::Missing("file tree"))?;
+            parse_file_tree(root, &mut Vec::new(), &mut tree)?;
+            if tree.is_empty() {
+                return Err(MetainfoError::Invalid("empty file tree"));
+            }
+            if !(piece_length as u32).is_power_of_two() || piece_length < 0x4000 {
+                return Err(MetainfoError::Invalid("v2 piece length shall be a power of two"));
+            }
+        }
+        let pieces = match dict.get("pieces") {
+            Some(pieces) => pieces.as_bytes().ok_or(MetainfoError::Missing("pieces"))?,
+            // v2 only torrent, the pieces are described by the file tree
+            None if v2 => &[],
+            None => return Err(MetainfoError::Missing("pieces")),
+        };
+        if 0 != pieces.len() % PIECE_HASH_LEN {
+            return Err(MetainfoError::Invalid("pieces is not a multiple of 20 bytes"));
+        }
+        if pieces.is_empty() && !v2 {
+            return Err(MetainfoError::Invalid("empty pieces"));
+        }
+        let private = dict.get("private").and_then(Value::as_int) == Some(1);
+
+        let mut files = Vec::new();
+        let multi_file = dict.get("files").is_some() ||
+            (pieces.is_empty() && (tree.len() > 1 || tree[0].path != [name]));
+        if pieces.is_empty() {
+            files.append(&mut tree);
+        } else if dict.get("files").is_some() {
+            let list = dict.get("files")
+                .and_then(Value::as_list)
+                .ok_or(MetainfoError::Missing("files"))?;
+            for file in list {
+                files.push(parse_file(file)?);
+            }
+        } else {
+            let length = dict.get("length")
+                .and_then(Value::as_int)
+                .ok_or(MetainfoError::Missing("length"))?;
+            if length < 0 {
+                return Err(MetainfoError::Invalid("negative file length"));
+            }
+            files.push(File {
+                length: length as u64,
+                path: vec![name.to_string()],
+                padding: false,
+                pieces_root: None,
+            });
+        }
+        if v2 && !pieces.is_empty() {
+            // hybrid torrent, both descriptions shall be the same content
+            let mut v2_files = tree.into_iter();
+            for file in files.iter_mut().filter(|file| !file.padding) {
+                match v2_files.next() {
+                    Some(ref v2_file) if v2_file.length == file.length => {
+                        file.pieces_root = v2_file.pieces_root.clone();
+                    }
+                    _ => return Err(MetainfoError::Invalid("v1 and v2 files don't match")),
+                }
+            }
+        }
+
+        let metainfo = Metainfo {
+            announce: None,
+            announce_list: Vec::new(),
+            url_list: Vec::new(),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            name: name.to_string(),
+            piece_length: piece_length as u32,
+            pieces: pieces.chunks(PIECE_HASH_LEN).map(Vec::from).collect(),
+            files: files,
+            multi_file: multi_file,
+            private: private,
+            info_hash: sha1(info),
+            info_hash_v2: if v2 { Some(sha256(info)) } else { None },
+            piece_layers: BTreeMap::new(),
+            info: Vec::from(info),
+        };
+        let expected = (metainfo.total_length() + piece_length as u64 - 1) / piece_length as u64;
+        if !pieces.is_empty() && expected != metainfo.pieces.len() as u64 {
+            return Err(MetainfoError::Invalid("piece count doesn't match total length"));
+        }
+        Ok(metainfo)
+    }
+
+    /// returns true if the torrent has v1 pieces, i.e. it is v1 or hybrid one
+    pub fn has_v1(&self) -> bool {
+        !self.pieces.is_empty()
+    }
+
+    /// returns true if the torrent has the v2 file tree, i.e. it is v2 or hybrid one
+    pub fn has_v2(&self) -> bool {
+        self.info_hash_v2.is_some()
+    }
+
+    /// returns sum of the file lengths
+    pub fn total_length(&self) -> u64 {
+        self.files.iter().map(|file| file.length).sum()
+    }
+
+    pub fn piece_count(&self) -> u32 {
+        self.pieces.len() as u32
+    }
+
+    /// returns length of the piece, the last one may be shorter
+    pub fn piece_len(&self, index: u32) -> u32 {
+        let offset = index as u64 * self.piece_length as u64;
+        let total = self.total_length();
+        if offset >= total {
+            0
+        } else if total - offset < self.piece_length as u64 {
+            (total - offset) as u32
+        } else {
+            self.piece_length
+        }
+    }
+
+    pub fn piece_hash(&self, index: u32) -> Option<&Sha1> {
+        self.pieces.get(index as usize)
+    }
+
+    /// returns requests (index, offset, length) which cover the piece
+    pub fn blocks(&self, index: u32) -> Vec<(u32, u32, u32)> {
+        let len = self.piece_len(index);
+        let mut blocks = Vec::new();
+        let mut offset = 0;
+        while offset < len {
+            let size = if len - offset < BLOCK_LEN {
+                len - offset
+            } else {
+                BLOCK_LEN
+            };
+            blocks.push((index, offset, size));
+            offset += size;
+        }
+        blocks
+    }
+}
+
+fn parse_file(file: &Value) -> Result<File, MetainfoError> {
+    let length = file.get("length").and_then(Value::as_int).ok_or(
+        MetainfoError::Missing("length"),
+    )?;
+    if length < 0 {
+        return Err(MetainfoError::Invalid("negative file length"));
+    }
+    let list = file.get("path").and_then(Value::as_list).ok_or(
+        MetainfoError::Missing("path"),
+    )?;
+    let mut path = Vec::new();
+    for component in list {
+        let component = component.as_str().ok_or(MetainfoError::Missing("path"))?;
+        check_path_component(component)?;
+        path.push(component.to_string());
+    }
+    if path.is_empty() {
+        return Err(MetainfoError::Invalid("empty file path"));
+    }
+    let padding = file.get("attr").and_then(Value::as_bytes).map_or(false, |attr| {
+        attr.contains(&b'p')
+    });
+    Ok(File {
+        length: length as u64,
+        path: path,
+        padding: padding,
+        pieces_root: None,
+    })
+}
+
+/// collects files of the v2 'file tree', the file is a dictionary with the empty key
+fn parse_file_tree(
+    node: &Value,
+    prefix: &mut Vec<String>,
+    files: &mut Vec<File>,
+) -> Result<(), MetainfoError> {
+    let dict = node.as_dict().ok_or(MetainfoError::Missing("file tree"))?;
+    for (name, child) in dict {
+        if name.is_empty() {
+            let length = child.get("length").and_then(Value::as_int).ok_or(
+                MetainfoError::Missing("length"),
+            )?;
+            if length < 0 {
+                return Err(MetainfoError::Invalid("negative file length"));
+            }
+            let root = child.get("pieces root").and_then(Value::as_bytes);
+            if length > 0 && root.map_or(true, |root| root.len() != SHA256_LEN) {
+                return Err(MetainfoError::Missing("pieces root"));
+            }
+            if prefix.is_empty() {
+                return Err(MetainfoError::Invalid("file without name"));
+            }
+            files.push(File {
+                length: length as u64,
+                path: prefix.clone(),
+                padding: false,
+                pieces_root: root.map(Vec::from),
+            });
+            continue;
+        }
+        let name = String::from_utf8(name.clone()).map_err(|_| {
+            MetainfoError::Invalid("file name is not UTF-8")
+        })?;
+        check_path_component(&name)?;
+        prefix.push(name);
+        parse_file_tree(child, prefix, files)?;
+        prefix.pop();
+    }
+    Ok(())
+}
+
+/// rejects names which could escape the download directory
+fn check_path_component(name: &str) -> Result<(), MetainfoError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') ||
+        name.contains('\\')
+    {
+        return Err(MetainfoError::Invalid("unsafe file path"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_pieces() {
+        let info = b"d6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces0:e";
+        match Metainfo::from_info(info) {
+            Err(MetainfoError::Invalid("empty pieces")) => {}
+            other => panic!("unexpected {:?}", other.map(|metainfo| metainfo.name)),
+        }
+        let mut data = Vec::from(&b"d4:info"[..]);
+        data.extend_from_slice(info);
+        data.push(b'e');
+        assert!(Metainfo::from_bytes(&data).is_err());
+    }
+}
@@ -0,0 +1,220 @@
+use std::io;
+use std::ptr;
+use std::slice;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::os::unix::io::AsRawFd;
+use std::collections::HashMap;
+
+use libc;
+
+use metainfo::Metainfo;
+use storage::{out_of_bounds, Layout, Storage};
+
+/// When the written pages are pushed to the disk
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Msync {
+    /// the kernel writes them back whenever it likes, flush does nothing
+    Never,
+    /// flush schedules the write back and returns (MS_ASYNC)
+    Async,
+    /// flush waits until the pages are written (MS_SYNC)
+    Sync,
+    /// every written block is synced before write_block returns
+    EveryWrite,
+}
+
+/// Shared mapping of the whole file
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+    writable: bool,
+}
+
+// the mapping is owned by the storage, it's never aliased
+unsafe impl Send for Mapping {}
+
+impl Mapping {
+    fn new(file: &fs::File, len: usize, writable: bool) -> io::Result<Self> {
+        let prot = if writable {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                prot,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping {
+            ptr: ptr as *mut u8,
+            len: len,
+            writable: writable,
+        })
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+
+    /// syncs the pages which hold the range
+    fn sync(&self, offset: usize, len: usize, flags: libc::c_int) -> io::Result<()> {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let start = offset / page * page;
+        let result = unsafe {
+            let addr = self.ptr.offset(start as isize) as *mut libc::c_void;
+            libc::msync(addr, offset + len - start, flags)
+        };
+        if 0 != result {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/// Keeps the files of the torrent in the download directory and accesses them through
+/// memory mappings: received blocks are copied into the mapping and uploaded ones are copied
+/// out of it once, without read or write calls, since a message block can't borrow
+/// the mapping; the files shall not be truncated by others while they are mapped
+pub struct MmapStorage {
+    root: PathBuf,
+    layout: Layout,
+    msync: Msync,
+    /// mappings of the opened files, empty and padding files are never mapped
+    mappings: HashMap<usize, Mapping>,
+}
+
+impl MmapStorage {
+    /// files are created with their full length on the first write
+    pub fn new<P: AsRef<Path>>(root: P, metainfo: &Metainfo) -> Self {
+        MmapStorage {
+            root: root.as_ref().to_path_buf(),
+            layout: Layout::new(metainfo),
+            msync: Msync::Sync,
+            mappings: HashMap::new(),
+        }
+    }
+
+    /// flush waits for the write back by default
+    pub fn set_msync(&mut self, msync: Msync) {
+        self.msync = msync;
+    }
+
+    /// returns the full path of the file
+    pub fn path(&self, file: usize) -> PathBuf {
+        self.root.join(&self.layout.files[file].path)
+    }
+
+    fn map(&mut self, file: usize, write: bool) -> io::Result<&mut Mapping> {
+        let remap = match self.mappings.get(&file) {
+            Some(mapping) => write && !mapping.writable,
+            None => true,
+        };
+        if remap {
+            let length = self.layout.files[file].length;
+            let path = self.path(file);
+            let handle = if write {
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                let handle = fs::OpenOptions::new().read(true).write(true).create(true).open(
+                    &path,
+                )?;
+                if handle.metadata()?.len() < length {
+                    handle.set_len(length)?;
+                }
+                handle
+            } else {
+                let handle = fs::File::open(&path)?;
+                if handle.metadata()?.len() < length {
+                    // the mapping beyond the end of the file would raise SIGBUS
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "file is shorter than in the torrent",
+                    ));
+                }
+                handle
+            };
+            let mapping = Mapping::new(&handle, length as usize, write)?;
+            self.mappings.insert(file, mapping);
+        }
+        Ok(self.mappings.get_mut(&file).unwrap())
+    }
+}
+
+impl Storage for MmapStorage {
+    fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    fn read_block(&mut self, index: u32, offset: u32, length: u32) -> io::Result<Vec<u8>> {
+        let slices = self.layout.slices(index, offset, length).ok_or_else(out_of_bounds)?;
+        let mut block = vec![0; length as usize];
+        let mut pos = 0;
+        for slice in slices {
+            if !self.layout.files[slice.file].padding {
+                let start = slice.offset as usize;
+                let mapping = self.map(slice.file, false)?;
+                block[pos..pos + slice.length].copy_from_slice(
+                    &mapping.as_slice()[start..start + slice.length],
+                );
+            }
+            pos += slice.length;
+        }
+        Ok(block)
+    }
+
+    fn write_block(&mut self, index: u32, offset: u32, data: &[u8]) -> io::Result<()> {
+        let slices = self.layout.slices(index, offset, data.len() as u32).ok_or_else(
+            out_of_bounds,
+        )?;
+        let msync = self.msync;
+        let mut pos = 0;
+        for slice in slices {
+            if !self.layout.files[slice.file].padding {
+                let start = slice.offset as usize;
+                let mapping = self.map(slice.file, true)?;
+                mapping.as_mut_slice()[start..start + slice.length].copy_from_slice(
+                    &data[pos..pos + slice.length],
+                );
+                if Msync::EveryWrite == msync {
+                    mapping.sync(start, slice.length, libc::MS_SYNC)?;
+                }
+            }
+            pos += slice.length;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let flags = match self.msync {
+            Msync::Never | Msync::EveryWrite => return Ok(()),
+            Msync::Async => libc::MS_ASYNC,
+            Msync::Sync => libc::MS_SYNC,
+        };
+        for mapping in self.mappings.values().filter(|mapping| mapping.writable) {
+            mapping.sync(0, mapping.len, flags)?;
+        }
+        Ok(())
+    }
+}
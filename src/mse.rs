@@ -0,0 +1,431 @@
+use std::io::{self, Read, Write};
+use std::cmp;
+
+use futures::{future, Async, Future, Poll};
+use futures::future::Loop;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io::{read_exact, write_all};
+use crypto::rc4::Rc4;
+use crypto::symmetriccipher::SynchronousStreamCipher;
+use byteorder::{ByteOrder, BigEndian};
+use rand::{self, Rng};
+
+use dh::{self, KeyPair};
+use hash::sha1;
+use PeerError;
+
+// verification constant
+const VC: [u8; 8] = [0; 8];
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+const MAX_PAD_LEN: usize = 512;
+const HASH_LEN: usize = 20;
+// first bytes of the RC4 key stream are discarded
+const DISCARD_LEN: usize = 1024;
+// <PSTRLIN: u8><PSTR: 'BitTorrent protocol'> starts every plaintext connection
+const PROTOCOL: &'static [u8] = b"\x13BitTorrent protocol";
+
+/// Encryption policy of the connection
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Encryption {
+    /// plaintext connections only
+    Disabled,
+    /// encryption is preferred, plaintext is accepted
+    Enabled,
+    /// RC4 encrypted connections only
+    Forced,
+}
+
+pub type MseConnection<S> = Box<Future<Item = MseStream<S>, Error = PeerError>>;
+pub type MseIncoming<S> = Box<Future<Item = (MseStream<S>, Option<Vec<u8>>), Error = PeerError>>;
+
+/// Stream which obfuscates traffic with RC4 after the MSE handshake is done
+pub struct MseStream<S> {
+    inner: S,
+    encryptor: Option<Rc4>,
+    decryptor: Option<Rc4>,
+    // received during the handshake and already decrypted
+    received: Vec<u8>,
+    // encrypted but not yet written
+    pending: Vec<u8>,
+}
+
+impl<S> MseStream<S> {
+    /// returns stream which passes data as is
+    pub fn plain(inner: S) -> Self {
+        Self::new(inner, None, None, Vec::new())
+    }
+
+    fn new(inner: S, encryptor: Option<Rc4>, decryptor: Option<Rc4>, received: Vec<u8>) -> Self {
+        MseStream {
+            inner: inner,
+            encryptor: encryptor,
+            decryptor: decryptor,
+            received: received,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encryptor.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: Write> MseStream<S> {
+    fn flush_pending(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            let len = self.inner.write(&self.pending)?;
+            if 0 == len {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write encrypted data",
+                ));
+            }
+            self.pending.drain(..len);
+        }
+        Ok(())
+    }
+}
+
+impl<S: Read> Read for MseStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.received.is_empty() {
+            let len = cmp::min(buf.len(), self.received.len());
+            buf[..len].copy_from_slice(&self.received[..len]);
+            self.received.drain(..len);
+            return Ok(len);
+        }
+        let len = self.inner.read(buf)?;
+        if let Some(ref mut decryptor) = self.decryptor {
+            apply(decryptor, &mut buf[..len]);
+        }
+        Ok(len)
+    }
+}
+
+impl<S: Write> Write for MseStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.encryptor.is_none() {
+            return self.inner.write(buf);
+        }
+        // key stream can't be rewound, so the data is accepted only when
+        // everything encrypted before has been written
+        self.flush_pending()?;
+        let mut data = buf.to_vec();
+        if let Some(ref mut encryptor) = self.encryptor {
+            apply(encryptor, &mut data);
+        }
+        self.pending = data;
+        match self.flush_pending() {
+            Ok(()) => Ok(buf.len()),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(buf.len()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_pending()?;
+        self.inner.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for MseStream<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for MseStream<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self.flush_pending() {
+            Ok(()) => self.inner.shutdown(),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Performs the handshake of the connection initiator (A side), info hash is used as SKEY
+pub fn connect<S>(stream: S, info_hash: &[u8], encryption: Encryption) -> MseConnection<S>
+where
+    S: AsyncRead + AsyncWrite + 'static,
+{
+    let provide = match encryption {
+        Encryption::Disabled => return Box::new(future::ok(MseStream::plain(stream))),
+        Encryption::Enabled => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+        Encryption::Forced => CRYPTO_RC4,
+    };
+    // v2 info hash is truncated like in the handshake
+    let skey = Vec::from(&info_hash[..cmp::min(info_hash.len(), HASH_LEN)]);
+    let keys = KeyPair::new();
+
+    // 1 A->B: Diffie Hellman Ya, PadA
+    let mut hello = Vec::from(keys.public());
+    hello.extend_from_slice(&padding());
+    let exchange = write_all(stream, hello)
+        .and_then(|(stream, _)| read_exact(stream, vec![0u8; dh::KEY_LEN]))
+        .map_err(PeerError::from);
+
+    // 2 B->A: Diffie Hellman Yb, PadB
+    Box::new(exchange.and_then(move |(stream, remote)| {
+        let secret = keys.secret(&remote);
+        let mut encryptor = cipher(b"keyA", &secret, &skey);
+        let mut decryptor = cipher(b"keyB", &secret, &skey);
+
+        // 3 A->B: HASH('req1', S), HASH('req2', SKEY) xor HASH('req3', S),
+        //   ENCRYPT(VC, crypto_provide, len(PadC), PadC, len(IA)), ENCRYPT(IA)
+        let mut request = hash(b"req1", &secret);
+        request.extend_from_slice(&xor(&hash(b"req2", &skey), &hash(b"req3", &secret)));
+        let mut payload = Vec::from(&VC[..]);
+        add_u32(&mut payload, provide);
+        add_u16(&mut payload, 0); // len(PadC)
+        add_u16(&mut payload, 0); // len(IA)
+        apply(&mut encryptor, &mut payload);
+        request.extend_from_slice(&payload);
+
+        // 4 B->A: ENCRYPT(VC, crypto_select, len(padD), padD), ENCRYPT2(Payload Stream)
+        let mut vc = Vec::from(&VC[..]);
+        apply(&mut decryptor, &mut vc);
+        write_all(stream, request)
+            .map_err(PeerError::from)
+            .and_then(move |(stream, _)| synchronize(stream, vc, MAX_PAD_LEN))
+            .and_then(|stream| read_exact(stream, [0u8; 6]).map_err(PeerError::from))
+            .and_then(move |(stream, mut select)| {
+                apply(&mut decryptor, &mut select);
+                let crypto = BigEndian::read_u32(&select[..4]);
+                let pad_len = BigEndian::read_u16(&select[4..]) as usize;
+                if pad_len > MAX_PAD_LEN {
+                    return future::Either::A(future::err(
+                        PeerError::Encryption("PadD is too long"),
+                    ));
+                }
+                future::Either::B(
+                    read_exact(stream, vec![0u8; pad_len])
+                        .map_err(PeerError::from)
+                        .and_then(move |(stream, mut pad)| {
+                            apply(&mut decryptor, &mut pad);
+                            if crypto == CRYPTO_RC4 && 0 != provide & CRYPTO_RC4 {
+                                let encryptor = Some(encryptor);
+                                let decryptor = Some(decryptor);
+                                Ok(MseStream::new(stream, encryptor, decryptor, Vec::new()))
+                            } else if crypto == CRYPTO_PLAINTEXT && 0 != provide & CRYPTO_PLAINTEXT {
+                                Ok(MseStream::plain(stream))
+                            } else {
+                                Err(PeerError::Encryption("Unexpected crypto_select"))
+                            }
+                        }),
+                )
+            })
+    }))
+}
+
+/// Performs the handshake of the receiving side (B side), SKEY is looked up among info hashes.
+/// Plaintext connections are detected by the protocol string unless encryption is forced.
+/// Returns the stream and the info hash the peer has used as SKEY.
+pub fn accept<S>(stream: S, info_hashes: Vec<Vec<u8>>, encryption: Encryption) -> MseIncoming<S>
+where
+    S: AsyncRead + AsyncWrite + 'static,
+{
+    let head = read_exact(stream, vec![0u8; PROTOCOL.len()]).map_err(PeerError::from);
+    Box::new(head.and_then(move |(stream, head)| {
+        let plaintext = head.as_slice() == PROTOCOL || encryption == Encryption::Disabled;
+        if plaintext && encryption == Encryption::Forced {
+            return future::Either::A(future::err(
+                PeerError::Encryption("Plaintext connection is not allowed"),
+            ));
+        } else if plaintext {
+            let stream = MseStream::new(stream, None, None, head);
+            return future::Either::A(future::ok((stream, None)));
+        }
+        future::Either::B(handshake(stream, head, info_hashes, encryption))
+    }))
+}
+
+fn handshake<S>(
+    stream: S,
+    head: Vec<u8>,
+    info_hashes: Vec<Vec<u8>>,
+    encryption: Encryption,
+) -> MseIncoming<S>
+where
+    S: AsyncRead + AsyncWrite + 'static,
+{
+    let keys = KeyPair::new();
+
+    // 1 A->B: Diffie Hellman Ya, PadA
+    let remote = read_exact(stream, vec![0u8; dh::KEY_LEN - head.len()])
+        .map_err(PeerError::from)
+        .and_then(move |(stream, tail)| {
+            let mut remote = head;
+            remote.extend_from_slice(&tail);
+            // 2 B->A: Diffie Hellman Yb, PadB
+            let mut hello = Vec::from(keys.public());
+            hello.extend_from_slice(&padding());
+            let secret = keys.secret(&remote);
+            write_all(stream, hello).map_err(PeerError::from).map(
+                move |(stream, _)| (stream, secret),
+            )
+        });
+
+    // 3 A->B: HASH('req1', S), HASH('req2', SKEY) xor HASH('req3', S),
+    //   ENCRYPT(VC, crypto_provide, len(PadC), PadC, len(IA)), ENCRYPT(IA)
+    let request = remote.and_then(|(stream, secret)| {
+        let req1 = hash(b"req1", &secret);
+        synchronize(stream, req1, MAX_PAD_LEN)
+            .and_then(|stream| {
+                read_exact(stream, vec![0u8; HASH_LEN]).map_err(PeerError::from)
+            })
+            .and_then(move |(stream, obfuscated)| {
+                let req3 = hash(b"req3", &secret);
+                let skey = info_hashes.into_iter().find(|skey| {
+                    let len = cmp::min(skey.len(), HASH_LEN);
+                    xor(&hash(b"req2", &skey[..len]), &req3) == obfuscated
+                });
+                match skey {
+                    Some(skey) => Ok((stream, secret, skey)),
+                    None => Err(PeerError::Encryption("Unknown SKEY")),
+                }
+            })
+    });
+
+    Box::new(request.and_then(move |(stream, secret, skey)| {
+        let mut decryptor = cipher(b"keyA", &secret, &skey);
+        let encryptor = cipher(b"keyB", &secret, &skey);
+        read_exact(stream, [0u8; 14])
+            .map_err(PeerError::from)
+            .and_then(move |(stream, mut header)| {
+                apply(&mut decryptor, &mut header);
+                let provide = BigEndian::read_u32(&header[8..12]);
+                let pad_len = BigEndian::read_u16(&header[12..]) as usize;
+                if &header[..8] != &VC[..] {
+                    return future::Either::A(future::err(
+                        PeerError::Encryption("Unexpected verification constant"),
+                    ));
+                }
+                if pad_len > MAX_PAD_LEN {
+                    return future::Either::A(future::err(
+                        PeerError::Encryption("PadC is too long"),
+                    ));
+                }
+                let initial = read_exact(stream, vec![0u8; pad_len + 2])
+                    .and_then(move |(stream, mut pad)| {
+                        apply(&mut decryptor, &mut pad);
+                        let ia_len = BigEndian::read_u16(&pad[pad_len..]) as usize;
+                        read_exact(stream, vec![0u8; ia_len]).map(
+                            move |(stream, mut ia)| {
+                                apply(&mut decryptor, &mut ia);
+                                (stream, ia, decryptor)
+                            },
+                        )
+                    })
+                    .map_err(PeerError::from);
+                future::Either::B(initial.and_then(move |(stream, ia, decryptor)| {
+                    respond(stream, ia, provide, encryption, encryptor, decryptor, skey)
+                }))
+            })
+    }))
+}
+
+// 4 B->A: ENCRYPT(VC, crypto_select, len(padD), padD), ENCRYPT2(Payload Stream)
+fn respond<S>(
+    stream: S,
+    ia: Vec<u8>,
+    provide: u32,
+    encryption: Encryption,
+    mut encryptor: Rc4,
+    decryptor: Rc4,
+    skey: Vec<u8>,
+) -> MseIncoming<S>
+where
+    S: AsyncRead + AsyncWrite + 'static,
+{
+    let crypto = if 0 != provide & CRYPTO_RC4 {
+        CRYPTO_RC4
+    } else if 0 != provide & CRYPTO_PLAINTEXT && encryption != Encryption::Forced {
+        CRYPTO_PLAINTEXT
+    } else {
+        return Box::new(future::err(
+            PeerError::Encryption("No acceptable crypto method"),
+        ));
+    };
+    let mut response = Vec::from(&VC[..]);
+    add_u32(&mut response, crypto);
+    add_u16(&mut response, 0); // len(padD)
+    apply(&mut encryptor, &mut response);
+    Box::new(write_all(stream, response).map_err(PeerError::from).map(
+        move |(stream, _)| if crypto == CRYPTO_RC4 {
+            let stream = MseStream::new(stream, Some(encryptor), Some(decryptor), ia);
+            (stream, Some(skey))
+        } else {
+            (MseStream::new(stream, None, None, ia), Some(skey))
+        },
+    ))
+}
+
+/// reads the stream until the pattern is found within limit bytes
+fn synchronize<S>(stream: S, pattern: Vec<u8>, limit: usize) -> Box<Future<Item = S, Error = PeerError>>
+where
+    S: AsyncRead + 'static,
+{
+    Box::new(future::loop_fn(
+        (stream, Vec::new()),
+        move |(stream, mut window)| {
+            let pattern = pattern.clone();
+            read_exact(stream, [0u8; 1]).map_err(PeerError::from).and_then(
+                move |(stream, byte)| {
+                    window.push(byte[0]);
+                    if window.ends_with(&pattern) {
+                        Ok(Loop::Break(stream))
+                    } else if window.len() >= limit + pattern.len() {
+                        Err(PeerError::Encryption("Synchronization pattern is not found"))
+                    } else {
+                        Ok(Loop::Continue((stream, window)))
+                    }
+                },
+            )
+        },
+    ))
+}
+
+fn hash(name: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut data = Vec::from(name);
+    data.extend_from_slice(value);
+    sha1(&data)
+}
+
+fn xor(lhs: &[u8], rhs: &[u8]) -> Vec<u8> {
+    lhs.iter().zip(rhs.iter()).map(|(l, r)| l ^ r).collect()
+}
+
+fn cipher(name: &[u8], secret: &[u8], skey: &[u8]) -> Rc4 {
+    let mut value = Vec::from(secret);
+    value.extend_from_slice(skey);
+    let mut rc4 = Rc4::new(&hash(name, &value));
+    let mut discard = [0u8; DISCARD_LEN];
+    apply(&mut rc4, &mut discard);
+    rc4
+}
+
+fn apply(rc4: &mut Rc4, data: &mut [u8]) {
+    let input = data.to_vec();
+    rc4.process(&input, data);
+}
+
+fn padding() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let mut pad = vec![0u8; rng.gen_range(0, MAX_PAD_LEN + 1)];
+    rng.fill_bytes(&mut pad);
+    pad
+}
+
+fn add_u32(buf: &mut Vec<u8>, value: u32) {
+    let mut container = [0u8; 4];
+    BigEndian::write_u32(&mut container, value);
+    buf.extend_from_slice(&container);
+}
+
+fn add_u16(buf: &mut Vec<u8>, value: u16) {
+    let mut container = [0u8; 2];
+    BigEndian::write_u16(&mut container, value);
+    buf.extend_from_slice(&container);
+}
@@ -0,0 +1,61 @@
+use std::io;
+use std::thread;
+use std::sync::Arc;
+
+/// Runs the job for every index of 0..count, thread N takes every N-th index.
+/// Every thread gets its own state from init, e.g. a buffer or an opened storage.
+/// Progress is called after each item; if it returns false the run stops with Interrupted.
+/// Returns results in the index order.
+pub fn run_parallel<S, T, I, F, P>(
+    count: usize,
+    threads: usize,
+    init: I,
+    job: F,
+    progress: P,
+) -> io::Result<Vec<T>>
+where
+    T: Send + 'static,
+    I: Fn() -> S + Send + Sync + 'static,
+    F: Fn(&mut S, usize) -> io::Result<T> + Send + Sync + 'static,
+    P: Fn(usize, &T) -> bool + Send + Sync + 'static,
+{
+    let shared = Arc::new((init, job, progress));
+    let workers = (0..threads)
+        .map(|first| {
+            let shared = shared.clone();
+            thread::spawn(move || -> io::Result<Vec<(usize, T)>> {
+                let (ref init, ref job, ref progress) = *shared;
+                let mut state = init();
+                let mut results = Vec::new();
+                for index in (first..count).filter(|index| index % threads == first) {
+                    let result = job(&mut state, index)?;
+                    if !progress(index, &result) {
+                        return Err(io::Error::new(io::ErrorKind::Interrupted, "run is stopped"));
+                    }
+                    results.push((index, result));
+                }
+                Ok(results)
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut results = (0..count).map(|_| None).collect::<Vec<Option<T>>>();
+    let mut error = None;
+    for worker in workers {
+        // every worker is joined, so none outlives the run
+        let done = worker.join().unwrap_or_else(|_| {
+            Err(io::Error::new(io::ErrorKind::Other, "worker thread has panicked"))
+        });
+        match done {
+            Ok(done) => {
+                for (index, result) in done {
+                    results[index] = Some(result);
+                }
+            }
+            Err(err) => error = error.or(Some(err)),
+        }
+    }
+    match error {
+        Some(err) => Err(err),
+        None => Ok(results.into_iter().map(Option::unwrap).collect()),
+    }
+}
@@ -0,0 +1,177 @@
+use std::cmp;
+use std::net::SocketAddr;
+use std::collections::{BTreeMap, HashMap};
+
+use metainfo::{Metainfo, BLOCK_LEN};
+use storage::Layout;
+
+/// Peer is banned after contributing to this number of pieces which failed the hash check
+const BAN_THRESHOLD: u32 = 3;
+
+/// Download state of the pieces, shared by the clients of the torrent
+pub struct Pieces {
+    layout: Layout,
+    have: Vec<bool>,
+    /// peers which sent the blocks of the incomplete pieces, index -> offset -> peer,
+    /// the peer is unknown for blocks restored from the resume data
+    blocks: HashMap<u32, HashMap<u32, Option<SocketAddr>>>,
+    /// number of the failed pieces the peer has sent blocks of
+    failures: HashMap<SocketAddr, u32>,
+    ban_threshold: u32,
+    /// bytes of the blocks received from peers
+    pub downloaded: u64,
+    /// bytes of the blocks sent to peers
+    pub uploaded: u64,
+}
+
+impl Pieces {
+    pub fn new(metainfo: &Metainfo) -> Self {
+        let layout = Layout::new(metainfo);
+        let count = layout.piece_count() as usize;
+        Pieces {
+            layout: layout,
+            have: vec![false; count],
+            blocks: HashMap::new(),
+            failures: HashMap::new(),
+            ban_threshold: BAN_THRESHOLD,
+            downloaded: 0,
+            uploaded: 0,
+        }
+    }
+
+    /// sets number of the failed pieces which makes the peer banned
+    pub fn set_ban_threshold(&mut self, failures: u32) {
+        self.ban_threshold = cmp::max(1, failures);
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// returns true if the piece is verified
+    pub fn has(&self, index: u32) -> bool {
+        self.have.get(index as usize).cloned().unwrap_or(false)
+    }
+
+    pub fn have_count(&self) -> u32 {
+        self.have.iter().filter(|&&have| have).count() as u32
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.have.iter().all(|&have| have)
+    }
+
+    /// returns requests (index, offset, length) which cover the piece
+    pub fn blocks(&self, index: u32) -> Vec<(u32, u32, u32)> {
+        let len = self.layout.piece_len(index);
+        (0..(len + BLOCK_LEN - 1) / BLOCK_LEN)
+            .map(|block| {
+                let offset = block * BLOCK_LEN;
+                (index, offset, cmp::min(BLOCK_LEN, len - offset))
+            })
+            .collect()
+    }
+
+    /// records the block received from the peer,
+    /// returns true if all blocks of the piece are received and it shall be verified
+    pub fn received(&mut self, index: u32, offset: u32, length: u32, peer: SocketAddr) -> bool {
+        if self.has(index) || !self.blocks(index).contains(&(index, offset, length)) {
+            return false;
+        }
+        self.downloaded += length as u64;
+        let count = self.blocks(index).len();
+        let blocks = self.blocks.entry(index).or_insert_with(HashMap::new);
+        blocks.insert(offset, Some(peer));
+        count == blocks.len()
+    }
+
+    /// returns true if the block of the incomplete piece is already received
+    pub fn is_received(&self, index: u32, offset: u32) -> bool {
+        self.blocks.get(&index).map_or(false, |blocks| blocks.contains_key(&offset))
+    }
+
+    /// returns offsets of the received blocks of the incomplete pieces
+    pub fn partial(&self) -> BTreeMap<u32, Vec<u32>> {
+        self.blocks
+            .iter()
+            .map(|(&index, blocks)| {
+                let mut offsets = blocks.keys().cloned().collect::<Vec<_>>();
+                offsets.sort();
+                (index, offsets)
+            })
+            .collect()
+    }
+
+    /// marks the block as received by an unknown peer, e.g. before the restart
+    pub fn restore_block(&mut self, index: u32, offset: u32) {
+        let known = self.blocks(index).iter().any(|&(_, start, _)| start == offset);
+        if known && !self.has(index) {
+            self.blocks.entry(index).or_insert_with(HashMap::new).insert(offset, None);
+        }
+    }
+
+    /// piece has matched its hash
+    pub fn verified(&mut self, index: u32) {
+        self.blocks.remove(&index);
+        if let Some(have) = self.have.get_mut(index as usize) {
+            *have = true;
+        }
+    }
+
+    /// forgets the piece, e.g. its data on the disk has changed
+    pub fn reset(&mut self, index: u32) {
+        self.blocks.remove(&index);
+        if let Some(have) = self.have.get_mut(index as usize) {
+            *have = false;
+        }
+    }
+
+    /// returns the verified pieces, the high bit of the first byte is the piece 0
+    pub fn bitfield(&self) -> Vec<u8> {
+        let mut bits = vec![0u8; (self.have.len() + 7) / 8];
+        for (index, _) in self.have.iter().enumerate().filter(|&(_, &have)| have) {
+            bits[index / 8] |= 0b1000_0000u8 >> (index % 8);
+        }
+        bits
+    }
+
+    /// replaces the state by the bitfield of the verified pieces, e.g. the recheck result
+    pub fn set_bitfield(&mut self, bits: &[u8]) {
+        for index in 0..self.have.len() as u32 {
+            let have = bits.get(index as usize / 8).map_or(false, |byte| {
+                0 != byte & (0b1000_0000u8 >> (index % 8))
+            });
+            if have {
+                self.verified(index);
+            } else if self.has(index) {
+                self.reset(index);
+            }
+        }
+    }
+
+    /// piece hasn't matched its hash: every peer which sent its blocks gets a failure,
+    /// returns requests to download the piece again
+    pub fn failed(&mut self, index: u32) -> Vec<(u32, u32, u32)> {
+        let mut peers = self.blocks
+            .remove(&index)
+            .map(|blocks| blocks.values().filter_map(|&peer| peer).collect::<Vec<_>>())
+            .unwrap_or_default();
+        peers.sort();
+        peers.dedup();
+        for peer in peers {
+            println!("Pieces::failed() piece {} has a block from {}", index, peer);
+            *self.failures.entry(peer).or_insert(0) += 1;
+        }
+        self.blocks(index)
+    }
+
+    /// returns number of the failed pieces the peer has sent blocks of
+    pub fn failures(&self, peer: &SocketAddr) -> u32 {
+        self.failures.get(peer).cloned().unwrap_or(0)
+    }
+
+    /// returns true if the peer has sent too many bad blocks
+    pub fn is_banned(&self, peer: &SocketAddr) -> bool {
+        self.failures(peer) >= self.ban_threshold
+    }
+}
@@ -7,15 +7,44 @@ use tokio_proto::pipeline::ServerProto;
 use Message;
 use Messages;
 use PeerCodec;
+use codec;
+
+pub struct PeerProto {
+    max_frame_size: usize,
+    split_handshake: bool,
+}
+impl PeerProto {
+    pub fn new() -> Self {
+        Self::with_max_frame_size(codec::MAX_FRAME_SIZE)
+    }
+
+    /// returns protocol which rejects frames with payload longer than max_frame_size bytes
+    pub fn with_max_frame_size(max_frame_size: usize) -> Self {
+        PeerProto {
+            max_frame_size: max_frame_size,
+            split_handshake: false,
+        }
+    }
+
+    /// see PeerCodec::set_split_handshake
+    pub fn set_split_handshake(&mut self, enabled: bool) {
+        self.split_handshake = enabled;
+    }
+
+    fn codec(&self) -> PeerCodec {
+        let mut codec = PeerCodec::with_max_frame_size(self.max_frame_size);
+        codec.set_split_handshake(self.split_handshake);
+        codec
+    }
+}
 
-pub struct PeerProto;
 impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for PeerProto {
     type Request = Messages;
     type Response = Message;
     type Transport = Framed<T, PeerCodec>;
     type BindTransport = Result<Self::Transport, io::Error>;
     fn bind_transport(&self, io: T) -> Self::BindTransport {
-        Ok(io.framed(PeerCodec))
+        Ok(io.framed(self.codec()))
     }
 }
 
@@ -25,6 +54,6 @@ impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for PeerProto {
     type Transport = Framed<T, PeerCodec>;
     type BindTransport = Result<Self::Transport, io::Error>;
     fn bind_transport(&self, io: T) -> Self::BindTransport {
-        Ok(io.framed(PeerCodec))
+        Ok(io.framed(self.codec()))
     }
 }
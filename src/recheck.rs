@@ -0,0 +1,156 @@
+use std::io;
+use std::cmp;
+use std::thread;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use futures::{Async, Poll, Stream};
+use futures::sync::mpsc;
+
+use storage::Storage;
+use parallel::run_parallel;
+
+const RECHECK_THREADS: usize = 4;
+
+/// Progress of the recheck
+#[derive(PartialEq, Debug, Clone)]
+pub enum RecheckEvent {
+    /// index, true if the piece matches its hash, number of the checked pieces, piece count
+    Piece(u32, bool, u32, u32),
+    /// all pieces are checked, bitfield of the valid ones
+    Done(Vec<u8>),
+}
+
+/// Reads every piece from the storage and verifies it
+pub struct Recheck {
+    threads: usize,
+    /// bytes per second read by all threads together
+    rate_limit: Option<u64>,
+}
+
+impl Recheck {
+    pub fn new() -> Self {
+        Recheck {
+            threads: RECHECK_THREADS,
+            rate_limit: None,
+        }
+    }
+
+    /// sets number of the threads reading and hashing pieces
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = cmp::max(1, threads);
+    }
+
+    /// limits the storage reads in bytes per second, None removes the limit
+    pub fn set_rate_limit(&mut self, bytes_per_second: Option<u64>) {
+        self.rate_limit = bytes_per_second.map(|rate| cmp::max(1, rate));
+    }
+
+    /// starts the recheck in the background, every thread reads its own storage
+    /// returned by open; the stream ends after the Done event or the first error
+    pub fn start<S, F>(&self, open: F) -> RecheckStream
+    where
+        S: Storage,
+        F: Fn() -> S + Send + Sync + 'static,
+    {
+        let (sender, receiver) = mpsc::unbounded();
+        let threads = self.threads;
+        let limiter = self.rate_limit.map(|rate| Mutex::new(RateLimit::new(rate)));
+        thread::spawn(move || {
+            let count = open().layout().piece_count() as usize;
+            let checked = AtomicUsize::new(0);
+            let progress = sender.clone();
+            let valid = run_parallel(
+                count,
+                threads,
+                move || open(),
+                move |storage, index| {
+                    if let Some(ref limiter) = limiter {
+                        let length = storage.layout().piece_len(index as u32);
+                        let delay = limiter.lock().unwrap().delay(length as u64);
+                        thread::sleep(delay);
+                    }
+                    storage.verify_piece(index as u32)
+                },
+                move |index, &valid| {
+                    let done = checked.fetch_add(1, Ordering::SeqCst) + 1;
+                    let event = RecheckEvent::Piece(index as u32, valid, done as u32, count as u32);
+                    // stops the run if the receiver is gone, nobody needs the result
+                    progress.unbounded_send(Ok(event)).is_ok()
+                },
+            );
+            let valid = match valid {
+                Ok(valid) => valid,
+                Err(err) => {
+                    let _ = sender.unbounded_send(Err(err));
+                    return;
+                }
+            };
+            let mut bits = vec![0u8; (count + 7) / 8];
+            for (index, _) in valid.into_iter().enumerate().filter(|&(_, valid)| valid) {
+                bits[index / 8] |= 0b1000_0000u8 >> (index % 8);
+            }
+            let _ = sender.unbounded_send(Ok(RecheckEvent::Done(bits)));
+        });
+        RecheckStream { inner: receiver }
+    }
+
+    /// rechecks the storages and blocks until the bitfield of the valid pieces is ready
+    pub fn run<S, F>(&self, open: F) -> io::Result<Vec<u8>>
+    where
+        S: Storage,
+        F: Fn() -> S + Send + Sync + 'static,
+    {
+        for event in self.start(open).wait() {
+            if let RecheckEvent::Done(bits) = event? {
+                return Ok(bits);
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::Other, "recheck has stopped"))
+    }
+}
+
+/// Progress of the recheck running in the background
+pub struct RecheckStream {
+    inner: mpsc::UnboundedReceiver<io::Result<RecheckEvent>>,
+}
+
+impl Stream for RecheckStream {
+    type Item = RecheckEvent;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.inner.poll() {
+            Ok(Async::Ready(Some(Ok(event)))) => Ok(Async::Ready(Some(event))),
+            Ok(Async::Ready(Some(Err(err)))) => Err(err),
+            Ok(Async::Ready(None)) |
+            Err(()) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// Spreads the reads evenly over time
+struct RateLimit {
+    rate: u64,
+    start: Instant,
+    bytes: u64,
+}
+
+impl RateLimit {
+    fn new(rate: u64) -> Self {
+        RateLimit {
+            rate: rate,
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// accounts the read, returns the time to wait before it
+    fn delay(&mut self, bytes: u64) -> Duration {
+        let due = Duration::from_millis(self.bytes * 1000 / self.rate);
+        self.bytes += bytes;
+        due.checked_sub(self.start.elapsed()).unwrap_or(Duration::from_millis(0))
+    }
+}
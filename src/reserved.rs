@@ -0,0 +1,94 @@
+use std::fmt;
+use rustc_serialize::hex::ToHex;
+
+pub const RESERVED_LEN: usize = 8;
+
+// extension protocol (BEP 10): reserved[5] & 0x10
+const EXTENSION_PROTOCOL_BYTE: usize = 5;
+const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
+// fast extension (BEP 6): reserved[7] & 0x04
+const FAST_EXTENSION_BYTE: usize = 7;
+const FAST_EXTENSION_BIT: u8 = 0x04;
+// BitTorrent v2 (BEP 52): reserved[7] & 0x10
+const V2_BYTE: usize = 7;
+const V2_BIT: u8 = 0x10;
+
+/// Reserved bytes of the handshake, every bit announces a protocol extension
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Reserved {
+    bytes: [u8; RESERVED_LEN],
+}
+
+impl Reserved {
+    /// returns reserved bytes with the extensions supported by this crate for every torrent,
+    /// v2 support depends on the torrent and is set separately
+    pub fn new() -> Self {
+        let mut reserved = Self::empty();
+        reserved.set_extension_protocol(true);
+        reserved.set_fast_extension(true);
+        reserved
+    }
+
+    /// returns reserved bytes without any extension
+    pub fn empty() -> Self {
+        Self { bytes: [0; RESERVED_LEN] }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut reserved = Self::empty();
+        reserved.bytes.copy_from_slice(&bytes[..RESERVED_LEN]);
+        reserved
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn extension_protocol(&self) -> bool {
+        self.get(EXTENSION_PROTOCOL_BYTE, EXTENSION_PROTOCOL_BIT)
+    }
+
+    pub fn set_extension_protocol(&mut self, enabled: bool) {
+        self.set(EXTENSION_PROTOCOL_BYTE, EXTENSION_PROTOCOL_BIT, enabled);
+    }
+
+    pub fn fast_extension(&self) -> bool {
+        self.get(FAST_EXTENSION_BYTE, FAST_EXTENSION_BIT)
+    }
+
+    pub fn set_fast_extension(&mut self, enabled: bool) {
+        self.set(FAST_EXTENSION_BYTE, FAST_EXTENSION_BIT, enabled);
+    }
+
+    pub fn v2(&self) -> bool {
+        self.get(V2_BYTE, V2_BIT)
+    }
+
+    pub fn set_v2(&mut self, enabled: bool) {
+        self.set(V2_BYTE, V2_BIT, enabled);
+    }
+
+    fn get(&self, byte: usize, bit: u8) -> bool {
+        0 != self.bytes[byte] & bit
+    }
+
+    fn set(&mut self, byte: usize, bit: u8, enabled: bool) {
+        if enabled {
+            self.bytes[byte] |= bit;
+        } else {
+            self.bytes[byte] &= !bit;
+        }
+    }
+}
+
+impl Default for Reserved {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for Reserved {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(fmt, "{}", self.bytes.to_hex())
+    }
+}
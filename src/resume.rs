@@ -0,0 +1,377 @@
+use std::io;
+use std::fmt;
+use std::error;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::collections::BTreeMap;
+use std::time::UNIX_EPOCH;
+
+use bencode::{self, BencodeError, Value};
+use storage::{Layout, Storage};
+use Pieces;
+
+/// Errors of the resume data
+#[derive(Debug)]
+pub enum ResumeError {
+    Bencode(BencodeError),
+    /// mandatory key is absent or has a wrong type
+    Missing(&'static str),
+    /// resume data belongs to another torrent
+    InfoHashMismatch,
+    /// resume data doesn't fit the torrent geometry
+    Invalid(&'static str),
+    Io(io::Error),
+}
+
+impl fmt::Display for ResumeError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &ResumeError::Bencode(ref err) => write!(fmt, "Bencode: {}", err),
+            &ResumeError::Missing(ref key) => write!(fmt, "Missing key '{}'", key),
+            &ResumeError::InfoHashMismatch => write!(fmt, "Resume data of another torrent"),
+            &ResumeError::Invalid(ref reason) => write!(fmt, "Invalid resume data: {}", reason),
+            &ResumeError::Io(ref err) => write!(fmt, "I/O error: {}", err),
+        }
+    }
+}
+
+impl error::Error for ResumeError {
+    fn description(&self) -> &str {
+        match self {
+            &ResumeError::Bencode(_) => "bencode error",
+            &ResumeError::Missing(_) => "missing key",
+            &ResumeError::InfoHashMismatch => "info hash mismatch",
+            &ResumeError::Invalid(_) => "invalid resume data",
+            &ResumeError::Io(_) => "I/O error",
+        }
+    }
+}
+
+impl From<BencodeError> for ResumeError {
+    fn from(err: BencodeError) -> ResumeError {
+        ResumeError::Bencode(err)
+    }
+}
+
+impl From<io::Error> for ResumeError {
+    fn from(err: io::Error) -> ResumeError {
+        ResumeError::Io(err)
+    }
+}
+
+/// Size and modification time of the file, zeros if the file doesn't exist
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct FileStat {
+    pub size: u64,
+    /// seconds since the Unix epoch
+    pub mtime: u64,
+}
+
+impl FileStat {
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(FileStat::default())
+            }
+            Err(err) => return Err(err),
+        };
+        let mtime = metadata.modified()?.duration_since(UNIX_EPOCH).map(|d| d.as_secs());
+        Ok(FileStat {
+            size: metadata.len(),
+            mtime: mtime.unwrap_or(0),
+        })
+    }
+}
+
+/// State of the torrent which allows to continue after the restart without rehashing
+#[derive(PartialEq, Debug, Clone)]
+pub struct ResumeData {
+    pub info_hash: Vec<u8>,
+    /// verified pieces, the high bit of the first byte is the piece 0
+    pub bitfield: Vec<u8>,
+    /// offsets of the received blocks of the incomplete pieces
+    pub partial: BTreeMap<u32, Vec<u32>>,
+    /// stats of the files in the layout order, the data is trusted only if they are the same
+    pub files: Vec<FileStat>,
+    pub peers: Vec<SocketAddr>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+impl ResumeData {
+    /// captures the state of the torrent which files are in the download directory
+    pub fn new<P: AsRef<Path>>(
+        info_hash: &[u8],
+        pieces: &Pieces,
+        dir: P,
+        peers: &[SocketAddr],
+    ) -> io::Result<Self> {
+        Ok(ResumeData {
+            info_hash: Vec::from(info_hash),
+            bitfield: pieces.bitfield(),
+            partial: pieces.partial(),
+            files: stat_files(pieces.layout(), dir.as_ref())?,
+            peers: Vec::from(peers),
+            uploaded: pieces.uploaded,
+            downloaded: pieces.downloaded,
+        })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ResumeError> {
+        let mut file = fs::File::open(path)?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        ResumeData::decode(&buf)
+    }
+
+    /// writes the data into the temporary file and renames it, so a crash never leaves
+    /// the truncated file behind
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let temp = path.with_extension("tmp");
+        {
+            let mut file = fs::File::create(&temp)?;
+            file.write_all(&self.encode())?;
+            file.sync_all()?;
+        }
+        fs::rename(&temp, path)
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, ResumeError> {
+        let root = bencode::decode_lenient(data)?;
+        let bytes = |key: &'static str| {
+            root.get(key).and_then(Value::as_bytes).ok_or(ResumeError::Missing(key))
+        };
+        let int = |key: &'static str| match root.get(key).and_then(Value::as_int) {
+            Some(value) if value >= 0 => Ok(value as u64),
+            Some(_) => Err(ResumeError::Invalid("negative number")),
+            None => Err(ResumeError::Missing(key)),
+        };
+
+        let mut partial = BTreeMap::new();
+        if let Some(dict) = root.get("partial").and_then(Value::as_dict) {
+            for (index, offsets) in dict {
+                let index = String::from_utf8_lossy(index).parse::<u32>().map_err(|_| {
+                    ResumeError::Invalid("partial piece index is not a number")
+                })?;
+                let offsets = offsets.as_list().ok_or(ResumeError::Missing("partial"))?;
+                let offsets = offsets
+                    .iter()
+                    .map(|offset| match offset.as_int() {
+                        Some(offset) if offset >= 0 && offset <= u32::max_value() as i64 => {
+                            Ok(offset as u32)
+                        }
+                        _ => Err(ResumeError::Invalid("bad block offset")),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                partial.insert(index, offsets);
+            }
+        }
+        let mut files = Vec::new();
+        for file in root.get("files").and_then(Value::as_list).ok_or(
+            ResumeError::Missing("files"),
+        )?
+        {
+            let size = file.get("size").and_then(Value::as_int);
+            let mtime = file.get("mtime").and_then(Value::as_int);
+            match (size, mtime) {
+                (Some(size), Some(mtime)) if size >= 0 && mtime >= 0 => {
+                    files.push(FileStat {
+                        size: size as u64,
+                        mtime: mtime as u64,
+                    })
+                }
+                _ => return Err(ResumeError::Invalid("bad file stat")),
+            }
+        }
+        let peers = root.get("peers")
+            .and_then(Value::as_list)
+            .map(|peers| {
+                // peers which can't be parsed are dropped, they are just hints
+                peers
+                    .iter()
+                    .filter_map(|peer| peer.as_str().and_then(|peer| peer.parse().ok()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(ResumeData {
+            info_hash: Vec::from(bytes("info-hash")?),
+            bitfield: Vec::from(bytes("pieces")?),
+            partial: partial,
+            files: files,
+            peers: peers,
+            uploaded: int("uploaded").unwrap_or(0),
+            downloaded: int("downloaded").unwrap_or(0),
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dict = BTreeMap::new();
+        {
+            let mut put = |key: &str, value: Value| {
+                dict.insert(Vec::from(key.as_bytes()), value);
+            };
+            put("info-hash", Value::Bytes(self.info_hash.clone()));
+            put("pieces", Value::Bytes(self.bitfield.clone()));
+            let mut partial = BTreeMap::new();
+            for (index, offsets) in &self.partial {
+                let offsets = offsets.iter().map(|&offset| Value::Int(offset as i64)).collect();
+                partial.insert(index.to_string().into_bytes(), Value::List(offsets));
+            }
+            put("partial", Value::Dict(partial));
+            let files = self.files
+                .iter()
+                .map(|stat| {
+                    let mut file = BTreeMap::new();
+                    file.insert(Vec::from(&b"mtime"[..]), Value::Int(stat.mtime as i64));
+                    file.insert(Vec::from(&b"size"[..]), Value::Int(stat.size as i64));
+                    Value::Dict(file)
+                })
+                .collect();
+            put("files", Value::List(files));
+            let peers = self.peers.iter().map(|peer| Value::from(peer.to_string().as_str()));
+            put("peers", Value::List(peers.collect()));
+            put("uploaded", Value::Int(self.uploaded as i64));
+            put("downloaded", Value::Int(self.downloaded as i64));
+        }
+        Value::Dict(dict).encode()
+    }
+
+    /// applies the data to the fresh state of the torrent; pieces of the files which size or
+    /// modification time has changed are verified against the storage instead,
+    /// returns number of the pieces rechecked this way, whether they have passed or not
+    pub fn restore<P: AsRef<Path>>(
+        &self,
+        info_hash: &[u8],
+        pieces: &mut Pieces,
+        storage: &mut Storage,
+        dir: P,
+    ) -> Result<u32, ResumeError> {
+        if self.info_hash != info_hash {
+            return Err(ResumeError::InfoHashMismatch);
+        }
+        let count = pieces.layout().piece_count();
+        if self.bitfield.len() != (count as usize + 7) / 8 {
+            return Err(ResumeError::Invalid("bitfield doesn't match the piece count"));
+        }
+        if self.files.len() != pieces.layout().files.len() {
+            return Err(ResumeError::Invalid("file count doesn't match"));
+        }
+        let current = stat_files(pieces.layout(), dir.as_ref())?;
+        let changed = current
+            .iter()
+            .zip(&self.files)
+            .map(|(current, saved)| current != saved)
+            .collect::<Vec<_>>();
+
+        let mut rechecked = 0;
+        for index in 0..count {
+            pieces.reset(index);
+            let files = pieces.layout().piece_files(index);
+            if files.iter().any(|&file| changed[file]) {
+                rechecked += 1;
+                if storage.verify_piece(index)? {
+                    pieces.verified(index);
+                }
+            } else if 0 != self.bitfield[index as usize / 8] & (0b1000_0000u8 >> (index % 8)) {
+                pieces.verified(index);
+            } else if let Some(offsets) = self.partial.get(&index) {
+                for &offset in offsets {
+                    pieces.restore_block(index, offset);
+                }
+            }
+        }
+        pieces.uploaded = self.uploaded;
+        pieces.downloaded = self.downloaded;
+        Ok(rechecked)
+    }
+}
+
+/// returns stats of the files in the download directory, padding files are never stored
+fn stat_files(layout: &Layout, dir: &Path) -> io::Result<Vec<FileStat>> {
+    layout
+        .files
+        .iter()
+        .map(|file| if file.padding {
+            Ok(FileStat::default())
+        } else {
+            FileStat::read(dir.join(&file.path))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use std::path::PathBuf;
+    use FileStorage;
+    use Metainfo;
+    use TorrentBuilder;
+
+    /// creates two files of two pieces each and the torrent of them
+    fn torrent(name: &str) -> (PathBuf, Metainfo) {
+        let dir = env::temp_dir().join(format!("torrent-peer-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("content")).unwrap();
+        fs::write(dir.join("content/a"), vec![1u8; 0x8000]).unwrap();
+        fs::write(dir.join("content/b"), vec![2u8; 0x8000]).unwrap();
+        let mut builder = TorrentBuilder::new(dir.join("content"));
+        builder.set_piece_length(0x4000);
+        let metainfo = builder.build().unwrap();
+        (dir, metainfo)
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut partial = BTreeMap::new();
+        partial.insert(3, vec![0, 0x4000]);
+        let data = ResumeData {
+            info_hash: vec![7; 20],
+            bitfield: vec![0b1010_0000],
+            partial: partial,
+            files: vec![FileStat { size: 5, mtime: 1_500_000_000 }, FileStat::default()],
+            peers: vec!["1.2.3.4:6881".parse().unwrap(), "[::1]:6882".parse().unwrap()],
+            uploaded: 10,
+            downloaded: 20,
+        };
+        assert_eq!(data, ResumeData::decode(&data.encode()).unwrap());
+        match ResumeData::decode(b"d5:filesle6:pieces0:e") {
+            Err(ResumeError::Missing("info-hash")) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn restore_changed_file() {
+        let (dir, metainfo) = torrent("resume");
+        let mut pieces = Pieces::new(&metainfo);
+        // piece 1 is stored, but it's not verified yet
+        pieces.verified(0);
+        pieces.verified(2);
+        pieces.verified(3);
+        let mut data = ResumeData::new(&metainfo.info_hash, &pieces, &dir, &[]).unwrap();
+        let mut storage = FileStorage::new(&dir, &metainfo);
+
+        let mut restored = Pieces::new(&metainfo);
+        let rechecked = data.restore(&metainfo.info_hash, &mut restored, &mut storage, &dir);
+        assert_eq!(0, rechecked.unwrap());
+        assert_eq!(pieces.bitfield(), restored.bitfield());
+
+        // only the pieces of b are rehashed, piece 3 is corrupted on the disk
+        data.files[1].mtime += 1;
+        let mut content = vec![2u8; 0x8000];
+        content[0x4000] = 0;
+        fs::write(dir.join("content/b"), content).unwrap();
+        let mut restored = Pieces::new(&metainfo);
+        let rechecked = data.restore(&metainfo.info_hash, &mut restored, &mut storage, &dir);
+        assert_eq!(2, rechecked.unwrap());
+        assert!(restored.has(0) && !restored.has(1));
+        assert!(restored.has(2) && !restored.has(3));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,190 @@
+use std::io;
+use std::io::Cursor;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, Bytes, IntoBuf};
+use bytes::buf::Chain;
+use futures::{task, Async, AsyncSink, Future, Poll, Sink, Stream};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::Framed;
+use tokio_core::reactor::{Handle, Interval};
+
+use PeerCodec;
+use PeerError;
+use Message;
+use codec;
+use client::PeerState;
+
+/// Timers are checked this often
+const TICK_MS: u64 = 1000;
+
+/// Header of the piece message followed by its block
+type PieceBuf = Chain<Cursor<Bytes>, Cursor<Bytes>>;
+
+/// Durations of the connection timers
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// KeepAlive is sent if nothing else was sent for this long
+    pub keep_alive: Duration,
+    /// peer shall answer the handshake in this time after the connection is established
+    pub handshake: Duration,
+    /// outstanding request is returned to the pool if its block doesn't arrive in this time
+    pub request: Duration,
+    /// peer is dropped if nothing is received from it for this long
+    pub idle: Duration,
+}
+
+impl Timeouts {
+    pub fn new() -> Self {
+        Timeouts {
+            keep_alive: Duration::from_secs(120),
+            handshake: Duration::from_secs(20),
+            request: Duration::from_secs(60),
+            idle: Duration::from_secs(180),
+        }
+    }
+}
+
+/// Drives the connection to the peer: reads messages as soon as they arrive and applies them
+/// to the shared state, sends messages from the outgoing queue of the state.
+/// It ends when the connection is closed, on the first error or when the state is not
+/// shared by anybody else; the state is marked closed then.
+/// Piece messages bypass the codec, their blocks are written to the stream as they are.
+pub struct PeerSession<T> {
+    transport: Framed<T, PeerCodec>,
+    state: Rc<RefCell<PeerState>>,
+    interval: Interval,
+    /// piece message which is being written
+    piece: Option<PieceBuf>,
+}
+
+impl<T: AsyncRead + AsyncWrite> PeerSession<T> {
+    pub fn new(
+        transport: Framed<T, PeerCodec>,
+        state: Rc<RefCell<PeerState>>,
+        handle: &Handle,
+    ) -> io::Result<Self> {
+        Ok(PeerSession {
+            transport: transport,
+            state: state,
+            interval: Interval::new(Duration::from_millis(TICK_MS), handle)?,
+            piece: None,
+        })
+    }
+
+    fn poll_session(&mut self) -> Poll<(), PeerError> {
+        self.state.borrow_mut().session = Some(task::current());
+        if 1 == Rc::strong_count(&self.state) {
+            // nobody is interested in the peer anymore
+            return Ok(Async::Ready(()));
+        }
+        while let Async::Ready(Some(())) = self.interval.poll()? {
+            self.state.borrow_mut().check_timers()?;
+        }
+        loop {
+            match self.transport.poll().map_err(PeerError::from)? {
+                Async::Ready(Some(mut messages)) => {
+                    let mut state = self.state.borrow_mut();
+                    state.messages.append(&mut messages);
+                    state.dispatch()?;
+                }
+                Async::Ready(None) => return Ok(Async::Ready(())),
+                Async::NotReady => break,
+            }
+        }
+        let mut state = self.state.borrow_mut();
+        state.fill_requests();
+        let mut serving = state.serve_requests();
+        while let Async::Ready(()) = write_piece(&mut self.transport, &mut self.piece)? {
+            let msg = match state.outgoing.pop_front() {
+                Some(msg) => msg,
+                None => break,
+            };
+            println!("PeerSession::poll() => {}", msg);
+            if let Message::Piece(index, begin, block) = msg {
+                // messages buffered by the codec precede the piece on the wire
+                if let Async::NotReady = self.transport.poll_complete().map_err(PeerError::from)? {
+                    state.outgoing.push_front(Message::Piece(index, begin, block));
+                    break;
+                }
+                let header = codec::piece_header(index, begin, block.len()).freeze();
+                self.piece = Some(header.into_buf().chain(block.into_buf()));
+                state.sent += 1;
+                state.last_sent = Instant::now();
+                if state.outgoing.is_empty() && serving {
+                    serving = state.serve_requests();
+                }
+                continue;
+            }
+            match self.transport.start_send(msg).map_err(PeerError::from)? {
+                AsyncSink::Ready => {
+                    state.sent += 1;
+                    state.last_sent = Instant::now();
+                    if state.outgoing.is_empty() && serving {
+                        serving = state.serve_requests();
+                    }
+                }
+                AsyncSink::NotReady(msg) => {
+                    state.outgoing.push_front(msg);
+                    break;
+                }
+            }
+        }
+        if self.piece.is_some() {
+            return Ok(Async::NotReady);
+        }
+        if let Async::Ready(()) = self.transport.poll_complete().map_err(PeerError::from)? {
+            if state.flushed != state.sent {
+                state.flushed = state.sent;
+                state.notify_waiters();
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> Future for PeerSession<T> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        match self.poll_session() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(())) => {
+                println!("PeerSession::poll() connection is closed");
+                self.state.borrow_mut().close(None);
+                Ok(Async::Ready(()))
+            }
+            Err(err) => {
+                println!("PeerSession::poll() connection is dropped: {}", err);
+                self.state.borrow_mut().close(Some(err));
+                Ok(Async::Ready(()))
+            }
+        }
+    }
+}
+
+/// writes the rest of the piece message, ready when nothing is left
+fn write_piece<T: AsyncWrite>(
+    transport: &mut Framed<T, PeerCodec>,
+    piece: &mut Option<PieceBuf>,
+) -> Poll<(), PeerError> {
+    if let Some(ref mut buf) = *piece {
+        while buf.has_remaining() {
+            match transport.get_mut().write_buf(buf)? {
+                Async::Ready(0) => {
+                    return Err(PeerError::from(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write piece",
+                    )))
+                }
+                Async::Ready(_) => {}
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+    *piece = None;
+    Ok(Async::Ready(()))
+}
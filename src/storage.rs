@@ -0,0 +1,273 @@
+use std::io;
+use std::cmp;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+
+use hash::{sha1, Sha1};
+use metainfo::Metainfo;
+
+/// File of the torrent placed in the piece space
+#[derive(PartialEq, Debug, Clone)]
+pub struct FileEntry {
+    /// path relative to the download directory, starts with the torrent name if multi-file
+    pub path: PathBuf,
+    /// offset of the first byte in the concatenation of all files
+    pub offset: u64,
+    pub length: u64,
+    /// padding file (BEP 47) is never stored, it reads as zeros
+    pub padding: bool,
+}
+
+/// Part of a block which belongs to a single file
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Slice {
+    /// index of the file in the layout
+    pub file: usize,
+    /// offset in the file
+    pub offset: u64,
+    pub length: usize,
+}
+
+/// Maps pieces onto the files, the files follow one another without gaps
+#[derive(PartialEq, Debug, Clone)]
+pub struct Layout {
+    pub piece_length: u32,
+    pub total_length: u64,
+    pub files: Vec<FileEntry>,
+    pub pieces: Vec<Sha1>,
+}
+
+impl Layout {
+    pub fn new(metainfo: &Metainfo) -> Self {
+        let mut files = Vec::new();
+        let mut offset = 0;
+        for file in &metainfo.files {
+            let mut path = PathBuf::new();
+            if metainfo.multi_file {
+                path.push(&metainfo.name);
+            }
+            for component in &file.path {
+                path.push(component);
+            }
+            files.push(FileEntry {
+                path: path,
+                offset: offset,
+                length: file.length,
+                padding: file.padding,
+            });
+            offset += file.length;
+        }
+        Layout {
+            piece_length: metainfo.piece_length,
+            total_length: offset,
+            files: files,
+            pieces: metainfo.pieces.clone(),
+        }
+    }
+
+    pub fn piece_count(&self) -> u32 {
+        self.pieces.len() as u32
+    }
+
+    /// returns length of the piece, the last one may be shorter
+    pub fn piece_len(&self, index: u32) -> u32 {
+        let offset = index as u64 * self.piece_length as u64;
+        if offset >= self.total_length {
+            0
+        } else {
+            cmp::min(self.piece_length as u64, self.total_length - offset) as u32
+        }
+    }
+
+    pub fn piece_hash(&self, index: u32) -> Option<&Sha1> {
+        self.pieces.get(index as usize)
+    }
+
+    /// splits the block of the piece into parts of the files it spans,
+    /// returns None if the block doesn't fit the piece
+    pub fn slices(&self, index: u32, offset: u32, length: u32) -> Option<Vec<Slice>> {
+        if offset as u64 + length as u64 > self.piece_len(index) as u64 {
+            return None;
+        }
+        let mut start = index as u64 * self.piece_length as u64 + offset as u64;
+        let end = start + length as u64;
+        let mut slices = Vec::new();
+        // files are sorted by offset, the first file ending after the start holds it
+        let first = self.files.iter().position(|file| file.offset + file.length > start);
+        for (index, file) in self.files.iter().enumerate().skip(first.unwrap_or(0)) {
+            if start == end {
+                break;
+            }
+            if 0 == file.length {
+                continue;
+            }
+            let len = cmp::min(end, file.offset + file.length) - start;
+            slices.push(Slice {
+                file: index,
+                offset: start - file.offset,
+                length: len as usize,
+            });
+            start += len;
+        }
+        Some(slices)
+    }
+
+    /// returns the pieces which contain data of the file, empty for an empty file
+    pub fn file_pieces(&self, file: usize) -> Range<u32> {
+        let file = &self.files[file];
+        if 0 == file.length {
+            return 0..0;
+        }
+        let first = file.offset / self.piece_length as u64;
+        let last = (file.offset + file.length - 1) / self.piece_length as u64;
+        first as u32..last as u32 + 1
+    }
+
+    /// returns the files the piece spans
+    pub fn piece_files(&self, index: u32) -> Vec<usize> {
+        let len = self.piece_len(index);
+        self.slices(index, 0, len).unwrap_or_default().iter().map(|slice| slice.file).collect()
+    }
+}
+
+/// Keeps the content of a torrent, block addresses are the ones of Request and Piece messages
+pub trait Storage {
+    fn layout(&self) -> &Layout;
+
+    /// reads the block of the piece
+    fn read_block(&mut self, index: u32, offset: u32, length: u32) -> io::Result<Vec<u8>>;
+
+    /// writes the block of the piece
+    fn write_block(&mut self, index: u32, offset: u32, data: &[u8]) -> io::Result<()>;
+
+    /// makes the written data durable
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// returns true if the piece is stored and matches its SHA-1
+    fn verify_piece(&mut self, index: u32) -> io::Result<bool> {
+        let expected = match self.layout().piece_hash(index) {
+            Some(hash) => hash.clone(),
+            None => return Ok(false),
+        };
+        let length = self.layout().piece_len(index);
+        match self.read_block(index, 0, length) {
+            Ok(piece) => Ok(sha1(&piece) == expected),
+            // the data is not written yet
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound ||
+                                err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Error of the block which is out of the piece bounds
+pub(crate) fn out_of_bounds() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "block is out of the piece bounds")
+}
+
+/// Keeps the files of the torrent in the download directory
+pub struct FileStorage {
+    root: PathBuf,
+    layout: Layout,
+    /// opened files and whether they are writable
+    handles: HashMap<usize, (fs::File, bool)>,
+}
+
+impl FileStorage {
+    /// files are created on the first write
+    pub fn new<P: AsRef<Path>>(root: P, metainfo: &Metainfo) -> Self {
+        FileStorage {
+            root: root.as_ref().to_path_buf(),
+            layout: Layout::new(metainfo),
+            handles: HashMap::new(),
+        }
+    }
+
+    /// returns the full path of the file
+    pub fn path(&self, file: usize) -> PathBuf {
+        self.root.join(&self.layout.files[file].path)
+    }
+
+    /// creates all files with their full length, the data which is not written reads as zeros
+    pub fn allocate(&mut self) -> io::Result<()> {
+        for index in 0..self.layout.files.len() {
+            if !self.layout.files[index].padding {
+                let length = self.layout.files[index].length;
+                let file = self.open(index, true)?;
+                if file.metadata()?.len() < length {
+                    file.set_len(length)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn open(&mut self, file: usize, write: bool) -> io::Result<&mut fs::File> {
+        let reopen = match self.handles.get(&file) {
+            Some(&(_, writable)) => write && !writable,
+            None => true,
+        };
+        if reopen {
+            let path = self.path(file);
+            let handle = if write {
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                fs::OpenOptions::new().read(true).write(true).create(true).open(&path)?
+            } else {
+                fs::File::open(&path)?
+            };
+            self.handles.insert(file, (handle, write));
+        }
+        Ok(&mut self.handles.get_mut(&file).unwrap().0)
+    }
+}
+
+impl Storage for FileStorage {
+    fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    fn read_block(&mut self, index: u32, offset: u32, length: u32) -> io::Result<Vec<u8>> {
+        let slices = self.layout.slices(index, offset, length).ok_or_else(out_of_bounds)?;
+        let mut block = vec![0; length as usize];
+        let mut pos = 0;
+        for slice in slices {
+            if !self.layout.files[slice.file].padding {
+                let file = self.open(slice.file, false)?;
+                file.seek(SeekFrom::Start(slice.offset))?;
+                file.read_exact(&mut block[pos..pos + slice.length])?;
+            }
+            pos += slice.length;
+        }
+        Ok(block)
+    }
+
+    fn write_block(&mut self, index: u32, offset: u32, data: &[u8]) -> io::Result<()> {
+        let slices = self.layout.slices(index, offset, data.len() as u32).ok_or_else(
+            out_of_bounds,
+        )?;
+        let mut pos = 0;
+        for slice in slices {
+            if !self.layout.files[slice.file].padding {
+                let file = self.open(slice.file, true)?;
+                file.seek(SeekFrom::Start(slice.offset))?;
+                file.write_all(&data[pos..pos + slice.length])?;
+            }
+            pos += slice.length;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for &mut (ref mut file, writable) in self.handles.values_mut() {
+            if writable {
+                file.sync_data()?;
+            }
+        }
+        Ok(())
+    }
+}
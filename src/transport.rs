@@ -0,0 +1,105 @@
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::{Future, Poll};
+use futures::future::Either;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use utp::{UtpSocket, UtpStream};
+
+/// Time given to uTP before falling back to TCP
+const UTP_CONNECT_TIMEOUT: u64 = 3;
+
+pub type PeerStreamConnect = Box<Future<Item = PeerStream, Error = io::Error>>;
+
+/// Transport used to connect to the peer
+#[derive(Clone)]
+pub enum Transport {
+    Tcp,
+    Utp(UtpSocket),
+    /// tries uTP first and falls back to TCP
+    Both(UtpSocket),
+}
+
+/// Byte stream of the peer connection over TCP or uTP
+pub enum PeerStream {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
+
+impl PeerStream {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            &PeerStream::Tcp(ref stream) => stream.peer_addr(),
+            &PeerStream::Utp(ref stream) => Ok(stream.peer_addr()),
+        }
+    }
+}
+
+pub fn connect(addr: &SocketAddr, handle: &Handle, transport: &Transport) -> PeerStreamConnect {
+    let addr = *addr;
+    match transport {
+        &Transport::Tcp => Box::new(TcpStream::connect(&addr, handle).map(PeerStream::Tcp)),
+        &Transport::Utp(ref socket) => Box::new(socket.connect(&addr).map(PeerStream::Utp)),
+        &Transport::Both(ref socket) => {
+            let handle = handle.clone();
+            let utp = socket.connect(&addr).map(PeerStream::Utp);
+            let utp: PeerStreamConnect =
+                match Timeout::new(Duration::from_secs(UTP_CONNECT_TIMEOUT), &handle) {
+                    Ok(timeout) => {
+                        Box::new(utp.select2(timeout).then(|result| match result {
+                            Ok(Either::A((stream, _))) => Ok(stream),
+                            Ok(Either::B(_)) => {
+                                Err(io::Error::new(io::ErrorKind::TimedOut, "uTP connect timeout"))
+                            }
+                            Err(Either::A((err, _))) |
+                            Err(Either::B((err, _))) => Err(err),
+                        }))
+                    }
+                    Err(_) => Box::new(utp),
+                };
+            Box::new(utp.or_else(move |_| {
+                TcpStream::connect(&addr, &handle).map(PeerStream::Tcp)
+            }))
+        }
+    }
+}
+
+impl Read for PeerStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            &mut PeerStream::Tcp(ref mut stream) => stream.read(buf),
+            &mut PeerStream::Utp(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for PeerStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            &mut PeerStream::Tcp(ref mut stream) => stream.write(buf),
+            &mut PeerStream::Utp(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            &mut PeerStream::Tcp(ref mut stream) => stream.flush(),
+            &mut PeerStream::Utp(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsyncRead for PeerStream {}
+
+impl AsyncWrite for PeerStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self {
+            &mut PeerStream::Tcp(ref mut stream) => AsyncWrite::shutdown(stream),
+            &mut PeerStream::Utp(ref mut stream) => stream.shutdown(),
+        }
+    }
+}
@@ -0,0 +1,836 @@
+use std::io::{self, Read, Write};
+use std::cmp;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::{Async, Future, Poll, Stream};
+use futures::task::{self, Task};
+use tokio_core::net::UdpSocket;
+use tokio_core::reactor::{Handle, Interval};
+use tokio_io::{AsyncRead, AsyncWrite};
+use byteorder::{ByteOrder, BigEndian};
+use rand::{self, Rng};
+
+// packet types
+const ST_DATA: u8 = 0;
+const ST_FIN: u8 = 1;
+const ST_STATE: u8 = 2;
+const ST_RESET: u8 = 3;
+const ST_SYN: u8 = 4;
+const VERSION: u8 = 1;
+
+const HEADER_LEN: usize = 20;
+const MAX_PACKET_LEN: usize = 1400;
+const MAX_PAYLOAD_LEN: usize = MAX_PACKET_LEN - HEADER_LEN;
+const RECV_BUFFER_LEN: usize = 1024 * 1024;
+
+// LEDBAT congestion control
+const TARGET_DELAY: u32 = 100_000;
+const MAX_CWND_INCREASE_PER_RTT: f64 = 3000.0;
+const MIN_WINDOW: usize = MAX_PAYLOAD_LEN;
+const INITIAL_WINDOW: usize = 3 * MAX_PAYLOAD_LEN;
+const DELAY_HISTORY_LEN: usize = 2;
+const DELAY_HISTORY_STEP: u64 = 60;
+
+const TICK_MS: u64 = 50;
+const MIN_RTO_MS: u64 = 500;
+const INITIAL_RTO_MS: u64 = 1000;
+const MAX_RETRANSMITS: u32 = 5;
+const DUPLICATE_ACKS: u32 = 3;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum State {
+    SynSent,
+    Connected,
+    Closed,
+    Reset,
+}
+
+struct Header {
+    kind: u8,
+    connection_id: u16,
+    timestamp: u32,
+    timestamp_diff: u32,
+    wnd_size: u32,
+    seq_nr: u16,
+    ack_nr: u16,
+}
+
+impl Header {
+    //<type: 4 bits><ver: 4 bits><extension: u8><connection_id: u16>
+    //  <timestamp_microseconds: u32><timestamp_difference_microseconds: u32>
+    //  <wnd_size: u32><seq_nr: u16><ack_nr: u16>
+    fn parse(buf: &[u8]) -> Option<(Header, &[u8])> {
+        if buf.len() < HEADER_LEN || buf[0] & 0x0F != VERSION || buf[0] >> 4 > ST_SYN {
+            return None;
+        }
+        let header = Header {
+            kind: buf[0] >> 4,
+            connection_id: BigEndian::read_u16(&buf[2..4]),
+            timestamp: BigEndian::read_u32(&buf[4..8]),
+            timestamp_diff: BigEndian::read_u32(&buf[8..12]),
+            wnd_size: BigEndian::read_u32(&buf[12..16]),
+            seq_nr: BigEndian::read_u16(&buf[16..18]),
+            ack_nr: BigEndian::read_u16(&buf[18..20]),
+        };
+        // skip extensions: <next extension: u8><len: u8><payload>
+        let mut extension = buf[1];
+        let mut offset = HEADER_LEN;
+        while 0 != extension {
+            if buf.len() < offset + 2 || buf.len() < offset + 2 + buf[offset + 1] as usize {
+                return None;
+            }
+            extension = buf[offset];
+            offset += 2 + buf[offset + 1] as usize;
+        }
+        Some((header, &buf[offset..]))
+    }
+
+    fn write(&self, buf: &mut Vec<u8>) {
+        let mut header = [0u8; HEADER_LEN];
+        header[0] = self.kind << 4 | VERSION;
+        BigEndian::write_u16(&mut header[2..4], self.connection_id);
+        BigEndian::write_u32(&mut header[4..8], self.timestamp);
+        BigEndian::write_u32(&mut header[8..12], self.timestamp_diff);
+        BigEndian::write_u32(&mut header[12..16], self.wnd_size);
+        BigEndian::write_u16(&mut header[16..18], self.seq_nr);
+        BigEndian::write_u16(&mut header[18..20], self.ack_nr);
+        buf.extend_from_slice(&header);
+    }
+}
+
+/// Datagrams to be sent by the driver, UdpSocket can be used only from a task
+type Outbox = VecDeque<(Vec<u8>, SocketAddr)>;
+
+/// Packet which waits for the acknowledgement
+struct Outgoing {
+    kind: u8,
+    seq_nr: u16,
+    payload: Vec<u8>,
+    sent: Instant,
+    transmissions: u32,
+}
+
+struct Connection {
+    addr: SocketAddr,
+    state: State,
+    recv_id: u16,
+    send_id: u16,
+    seq_nr: u16,
+    ack_nr: u16,
+    // sending side
+    unacked: VecDeque<Outgoing>,
+    in_flight: usize,
+    max_window: usize,
+    peer_window: usize,
+    fin_sent: bool,
+    duplicate_acks: u32,
+    rtt: u64,
+    rtt_var: u64,
+    rto: u64,
+    reply_micro: u32,
+    current_delay: u32,
+    delay_history: VecDeque<(Instant, u32)>,
+    // receiving side
+    received: Vec<u8>,
+    out_of_order: HashMap<u16, Vec<u8>>,
+    fin_nr: Option<u16>,
+    eof: bool,
+    dropped: bool,
+    reader: Option<Task>,
+    writer: Option<Task>,
+}
+
+impl Connection {
+    fn new(addr: SocketAddr, recv_id: u16, send_id: u16, state: State) -> Self {
+        Connection {
+            addr: addr,
+            state: state,
+            recv_id: recv_id,
+            send_id: send_id,
+            seq_nr: rand::thread_rng().gen(),
+            ack_nr: 0,
+            unacked: VecDeque::new(),
+            in_flight: 0,
+            max_window: INITIAL_WINDOW,
+            peer_window: MAX_PAYLOAD_LEN,
+            fin_sent: false,
+            duplicate_acks: 0,
+            rtt: 0,
+            rtt_var: 0,
+            rto: INITIAL_RTO_MS,
+            reply_micro: 0,
+            current_delay: 0,
+            delay_history: VecDeque::new(),
+            received: Vec::new(),
+            out_of_order: HashMap::new(),
+            fin_nr: None,
+            eof: false,
+            dropped: false,
+            reader: None,
+            writer: None,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        match self.state {
+            State::Closed | State::Reset => true,
+            // nobody is going to read the rest, so wait only for own FIN to be acked
+            _ => self.dropped && self.fin_sent && self.unacked.is_empty(),
+        }
+    }
+
+    fn window(&self) -> u32 {
+        let buffered = self.received.len() +
+            self.out_of_order.values().map(|p| p.len()).sum::<usize>();
+        RECV_BUFFER_LEN.saturating_sub(buffered) as u32
+    }
+
+    fn header(&self, kind: u8, seq_nr: u16) -> Header {
+        Header {
+            kind: kind,
+            connection_id: if kind == ST_SYN { self.recv_id } else { self.send_id },
+            timestamp: now_micros(),
+            timestamp_diff: self.reply_micro,
+            wnd_size: self.window(),
+            seq_nr: seq_nr,
+            ack_nr: self.ack_nr,
+        }
+    }
+
+    fn transmit(&self, outbox: &mut Outbox, kind: u8, seq_nr: u16, payload: &[u8]) {
+        let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+        self.header(kind, seq_nr).write(&mut buf);
+        buf.extend_from_slice(payload);
+        outbox.push_back((buf, self.addr));
+    }
+
+    /// sends a packet which consumes sequence number and waits for the ack
+    fn send(&mut self, outbox: &mut Outbox, kind: u8, payload: Vec<u8>) {
+        let seq_nr = self.seq_nr;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.transmit(outbox, kind, seq_nr, &payload);
+        self.in_flight += payload.len();
+        self.unacked.push_back(Outgoing {
+            kind: kind,
+            seq_nr: seq_nr,
+            payload: payload,
+            sent: Instant::now(),
+            transmissions: 1,
+        });
+    }
+
+    fn ack(&self, outbox: &mut Outbox) {
+        self.transmit(outbox, ST_STATE, self.seq_nr, &[]);
+    }
+
+    fn wake(&mut self) {
+        if let Some(task) = self.reader.take() {
+            task.notify();
+        }
+        if let Some(task) = self.writer.take() {
+            task.notify();
+        }
+    }
+
+    fn on_packet(&mut self, outbox: &mut Outbox, header: &Header, payload: &[u8]) {
+        self.peer_window = header.wnd_size as usize;
+        self.reply_micro = now_micros().wrapping_sub(header.timestamp);
+        if 0 != header.timestamp_diff {
+            self.on_delay(header.timestamp_diff);
+        }
+        match header.kind {
+            ST_RESET => {
+                self.state = State::Reset;
+                self.wake();
+                return;
+            }
+            ST_SYN => {
+                // our STATE was lost, repeat it
+                self.ack(outbox);
+                return;
+            }
+            ST_STATE if self.state == State::SynSent => {
+                self.state = State::Connected;
+                self.ack_nr = header.seq_nr.wrapping_sub(1);
+            }
+            _ => {}
+        }
+        self.on_ack(outbox, header);
+        match header.kind {
+            ST_DATA => self.on_data(outbox, header.seq_nr, payload),
+            ST_FIN => {
+                self.fin_nr = Some(header.seq_nr);
+                self.on_data(outbox, header.seq_nr, &[]);
+            }
+            _ => {}
+        }
+        self.wake();
+    }
+
+    fn on_ack(&mut self, outbox: &mut Outbox, header: &Header) {
+        let now = Instant::now();
+        let mut acked = 0;
+        while self.unacked.front().map_or(false, |p| !seq_less(header.ack_nr, p.seq_nr)) {
+            let packet = self.unacked.pop_front().unwrap();
+            acked += packet.payload.len();
+            if 1 == packet.transmissions {
+                self.on_rtt(millis(now - packet.sent));
+            }
+        }
+        self.in_flight -= acked;
+        if 0 != acked || self.unacked.is_empty() {
+            self.duplicate_acks = 0;
+            if 0 != acked {
+                self.on_window(acked);
+            }
+        } else if header.kind == ST_STATE {
+            self.duplicate_acks += 1;
+            if DUPLICATE_ACKS == self.duplicate_acks {
+                // fast retransmit of the first lost packet
+                self.max_window = cmp::max(MIN_WINDOW, self.max_window / 2);
+                self.retransmit(outbox);
+            }
+        }
+    }
+
+    fn on_data(&mut self, outbox: &mut Outbox, seq_nr: u16, payload: &[u8]) {
+        if seq_nr == self.ack_nr.wrapping_add(1) {
+            self.deliver(seq_nr, payload);
+            loop {
+                let next = self.ack_nr.wrapping_add(1);
+                match self.out_of_order.remove(&next) {
+                    Some(payload) => self.deliver(next, &payload),
+                    None => break,
+                }
+            }
+        } else if seq_less(self.ack_nr, seq_nr) && (self.window() as usize) >= payload.len() {
+            self.out_of_order.insert(seq_nr, Vec::from(payload));
+        }
+        self.ack(outbox);
+    }
+
+    fn deliver(&mut self, seq_nr: u16, payload: &[u8]) {
+        self.ack_nr = seq_nr;
+        self.received.extend_from_slice(payload);
+        if Some(seq_nr) == self.fin_nr {
+            self.eof = true;
+            if self.fin_sent && self.unacked.is_empty() {
+                self.state = State::Closed;
+            }
+        }
+    }
+
+    fn on_rtt(&mut self, sample: u64) {
+        if 0 == self.rtt {
+            self.rtt = sample;
+            self.rtt_var = sample / 2;
+        } else {
+            let delta = if self.rtt > sample {
+                self.rtt - sample
+            } else {
+                sample - self.rtt
+            };
+            self.rtt_var = (3 * self.rtt_var + delta) / 4;
+            self.rtt = (7 * self.rtt + sample) / 8;
+        }
+        self.rto = cmp::max(MIN_RTO_MS, self.rtt + 4 * self.rtt_var);
+    }
+
+    fn on_delay(&mut self, delay: u32) {
+        let now = Instant::now();
+        self.current_delay = delay;
+        let fresh = self.delay_history.back().map_or(false, |&(start, _)| {
+            now - start < Duration::from_secs(DELAY_HISTORY_STEP)
+        });
+        if fresh {
+            let last = self.delay_history.back_mut().unwrap();
+            last.1 = cmp::min(last.1, delay);
+        } else {
+            self.delay_history.push_back((now, delay));
+            if self.delay_history.len() > DELAY_HISTORY_LEN {
+                self.delay_history.pop_front();
+            }
+        }
+    }
+
+    /// LEDBAT: grows the window while the queuing delay is below target and shrinks it above
+    fn on_window(&mut self, acked: usize) {
+        let base_delay = self.delay_history.iter().map(|&(_, d)| d).min().unwrap_or(0);
+        let queuing_delay = cmp::min(self.current_delay.wrapping_sub(base_delay), 2 * TARGET_DELAY);
+        let off_target = (TARGET_DELAY as f64 - queuing_delay as f64) / TARGET_DELAY as f64;
+        let window_factor = acked as f64 / cmp::max(self.max_window, acked) as f64;
+        let gain = MAX_CWND_INCREASE_PER_RTT * off_target * window_factor;
+        let window = self.max_window as f64 + gain;
+        self.max_window = cmp::max(MIN_WINDOW, window as usize);
+    }
+
+    fn retransmit(&mut self, outbox: &mut Outbox) {
+        if let Some(packet) = self.unacked.front_mut() {
+            packet.sent = Instant::now();
+            packet.transmissions += 1;
+        }
+        if let Some(packet) = self.unacked.front() {
+            self.transmit(outbox, packet.kind, packet.seq_nr, &packet.payload);
+        }
+    }
+
+    fn on_tick(&mut self, outbox: &mut Outbox, now: Instant) {
+        let expired = self.unacked.front().map_or(false, |p| {
+            millis(now - p.sent) >= self.rto
+        });
+        if !expired {
+            return;
+        }
+        if self.unacked.front().map_or(false, |p| p.transmissions > MAX_RETRANSMITS) {
+            self.state = State::Reset;
+            self.wake();
+            return;
+        }
+        self.rto = cmp::min(2 * self.rto, 60_000);
+        self.max_window = MIN_WINDOW;
+        self.retransmit(outbox);
+    }
+
+    fn available(&self) -> usize {
+        let window = cmp::min(self.max_window, cmp::max(self.peer_window, MIN_WINDOW));
+        if 0 == self.in_flight {
+            // one packet is always allowed, otherwise a tiny window stalls the connection
+            cmp::max(window, MAX_PAYLOAD_LEN)
+        } else {
+            window.saturating_sub(self.in_flight)
+        }
+    }
+}
+
+struct Shared {
+    socket: UdpSocket,
+    outbox: Outbox,
+    driver: Option<Task>,
+    connections: HashMap<(SocketAddr, u16), Rc<RefCell<Connection>>>,
+    listening: bool,
+    incoming: VecDeque<Rc<RefCell<Connection>>>,
+    listener: Option<Task>,
+}
+
+impl Shared {
+    fn send(&mut self) {
+        while let Some((buf, addr)) = self.outbox.pop_front() {
+            match self.socket.send_to(&buf, &addr) {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    self.outbox.push_front((buf, addr));
+                    break;
+                }
+                // lost datagram is retransmitted like any other lost packet
+                _ => {}
+            }
+        }
+    }
+
+    /// the driver sends datagrams queued outside of its task
+    fn notify(&mut self) {
+        if !self.outbox.is_empty() {
+            if let Some(task) = self.driver.take() {
+                task.notify();
+            }
+        }
+    }
+
+    fn receive(&mut self) {
+        let mut buf = [0u8; 2 * MAX_PACKET_LEN];
+        loop {
+            match self.socket.recv_from(&mut buf) {
+                Ok((len, addr)) => {
+                    if let Some((header, payload)) = Header::parse(&buf[..len]) {
+                        self.dispatch(addr, &header, payload);
+                    }
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                // ICMP errors of previous datagrams are reported here
+                Err(_) => continue,
+            }
+        }
+        self.cleanup();
+    }
+
+    fn dispatch(&mut self, addr: SocketAddr, header: &Header, payload: &[u8]) {
+        let id = if header.kind == ST_SYN {
+            header.connection_id.wrapping_add(1)
+        } else {
+            header.connection_id
+        };
+        if let Some(connection) = self.connections.get(&(addr, id)) {
+            connection.borrow_mut().on_packet(&mut self.outbox, header, payload);
+            return;
+        }
+        if header.kind == ST_SYN && self.listening {
+            let mut connection = Connection::new(addr, id, header.connection_id, State::Connected);
+            connection.ack_nr = header.seq_nr;
+            connection.peer_window = header.wnd_size as usize;
+            connection.reply_micro = now_micros().wrapping_sub(header.timestamp);
+            connection.ack(&mut self.outbox);
+            let connection = Rc::new(RefCell::new(connection));
+            self.connections.insert((addr, id), connection.clone());
+            self.incoming.push_back(connection);
+            if let Some(task) = self.listener.take() {
+                task.notify();
+            }
+        } else if header.kind != ST_RESET {
+            let mut buf = Vec::with_capacity(HEADER_LEN);
+            Header {
+                kind: ST_RESET,
+                connection_id: header.connection_id,
+                timestamp: now_micros(),
+                timestamp_diff: 0,
+                wnd_size: 0,
+                seq_nr: 0,
+                ack_nr: header.seq_nr,
+            }.write(&mut buf);
+            self.outbox.push_back((buf, addr));
+        }
+    }
+
+    fn on_tick(&mut self) {
+        let now = Instant::now();
+        for connection in self.connections.values() {
+            connection.borrow_mut().on_tick(&mut self.outbox, now);
+        }
+        self.cleanup();
+    }
+
+    fn cleanup(&mut self) {
+        let done = self.connections
+            .iter()
+            .filter(|&(_, c)| c.borrow().is_done())
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        for key in done {
+            self.connections.remove(&key);
+        }
+    }
+}
+
+/// Receives datagrams and runs retransmission timers of all connections of the socket
+struct Driver {
+    shared: Rc<RefCell<Shared>>,
+    interval: Interval,
+}
+
+impl Future for Driver {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        let mut shared = self.shared.borrow_mut();
+        shared.driver = Some(task::current());
+        while let Ok(Async::Ready(Some(()))) = self.interval.poll() {
+            shared.on_tick();
+        }
+        shared.receive();
+        shared.send();
+        drop(shared);
+        if 1 == Rc::strong_count(&self.shared) {
+            // socket and all its streams are dropped
+            return Ok(Async::Ready(()));
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+/// UDP socket which carries uTP (BEP 29) connections
+#[derive(Clone)]
+pub struct UtpSocket {
+    shared: Rc<RefCell<Shared>>,
+}
+
+impl UtpSocket {
+    pub fn bind(addr: &SocketAddr, handle: &Handle) -> io::Result<UtpSocket> {
+        let shared = Rc::new(RefCell::new(Shared {
+            socket: UdpSocket::bind(addr, handle)?,
+            outbox: Outbox::new(),
+            driver: None,
+            connections: HashMap::new(),
+            listening: false,
+            incoming: VecDeque::new(),
+            listener: None,
+        }));
+        let driver = Driver {
+            shared: shared.clone(),
+            interval: Interval::new(Duration::from_millis(TICK_MS), handle)?,
+        };
+        handle.spawn(driver);
+        Ok(UtpSocket { shared: shared })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.borrow().socket.local_addr()
+    }
+
+    pub fn connect(&self, addr: &SocketAddr) -> UtpConnect {
+        let mut shared = self.shared.borrow_mut();
+        let mut recv_id: u16 = rand::thread_rng().gen();
+        while shared.connections.contains_key(&(*addr, recv_id)) {
+            recv_id = recv_id.wrapping_add(2);
+        }
+        let mut connection = Connection::new(*addr, recv_id, recv_id.wrapping_add(1), State::SynSent);
+        connection.seq_nr = 1;
+        connection.send(&mut shared.outbox, ST_SYN, Vec::new());
+        shared.notify();
+        let connection = Rc::new(RefCell::new(connection));
+        shared.connections.insert((*addr, recv_id), connection.clone());
+        UtpConnect {
+            socket: Some(self.clone()),
+            connection: connection,
+        }
+    }
+
+    /// returns stream of incoming connections
+    pub fn incoming(&self) -> UtpIncoming {
+        self.shared.borrow_mut().listening = true;
+        UtpIncoming { socket: self.clone() }
+    }
+}
+
+pub struct UtpConnect {
+    socket: Option<UtpSocket>,
+    connection: Rc<RefCell<Connection>>,
+}
+
+impl Future for UtpConnect {
+    type Item = UtpStream;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<UtpStream, io::Error> {
+        let mut connection = self.connection.borrow_mut();
+        match connection.state {
+            State::SynSent => {
+                connection.writer = Some(task::current());
+                Ok(Async::NotReady)
+            }
+            State::Connected => {
+                let socket = self.socket.take().expect("poll after connect");
+                Ok(Async::Ready(UtpStream::new(socket, self.connection.clone())))
+            }
+            _ => Err(io::Error::new(io::ErrorKind::ConnectionRefused, "uTP connect failed")),
+        }
+    }
+}
+
+impl Drop for UtpConnect {
+    fn drop(&mut self) {
+        // connect was abandoned, the driver forgets the half open connection
+        let mut connection = self.connection.borrow_mut();
+        if connection.state == State::SynSent {
+            connection.state = State::Closed;
+        }
+    }
+}
+
+pub struct UtpIncoming {
+    socket: UtpSocket,
+}
+
+impl Stream for UtpIncoming {
+    type Item = (UtpStream, SocketAddr);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        let mut shared = self.socket.shared.borrow_mut();
+        match shared.incoming.pop_front() {
+            Some(connection) => {
+                let addr = connection.borrow().addr;
+                let stream = UtpStream::new(self.socket.clone(), connection);
+                Ok(Async::Ready(Some((stream, addr))))
+            }
+            None => {
+                shared.listener = Some(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+/// Reliable ordered byte stream over uTP
+pub struct UtpStream {
+    socket: UtpSocket,
+    connection: Rc<RefCell<Connection>>,
+}
+
+impl UtpStream {
+    fn new(socket: UtpSocket, connection: Rc<RefCell<Connection>>) -> Self {
+        UtpStream {
+            socket: socket,
+            connection: connection,
+        }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.connection.borrow().addr
+    }
+
+    fn close(&self) {
+        let mut shared = self.socket.shared.borrow_mut();
+        let mut connection = self.connection.borrow_mut();
+        if !connection.fin_sent && connection.state == State::Connected {
+            connection.fin_sent = true;
+            connection.send(&mut shared.outbox, ST_FIN, Vec::new());
+            shared.notify();
+        }
+    }
+}
+
+fn would_block() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "would block")
+}
+
+impl Read for UtpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut shared = self.socket.shared.borrow_mut();
+        let mut connection = self.connection.borrow_mut();
+        if !connection.received.is_empty() {
+            let len = cmp::min(buf.len(), connection.received.len());
+            buf[..len].copy_from_slice(&connection.received[..len]);
+            let was_full = (connection.window() as usize) < MAX_PAYLOAD_LEN;
+            connection.received.drain(..len);
+            if was_full {
+                // let the peer know the window is open again
+                connection.ack(&mut shared.outbox);
+                shared.notify();
+            }
+            return Ok(len);
+        }
+        match connection.state {
+            _ if connection.eof => Ok(0),
+            State::Reset => Err(io::Error::new(io::ErrorKind::ConnectionReset, "uTP reset")),
+            State::Closed => Ok(0),
+            _ => {
+                connection.reader = Some(task::current());
+                Err(would_block())
+            }
+        }
+    }
+}
+
+impl Write for UtpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut shared = self.socket.shared.borrow_mut();
+        let mut connection = self.connection.borrow_mut();
+        match connection.state {
+            State::Reset => {
+                return Err(io::Error::new(io::ErrorKind::ConnectionReset, "uTP reset"))
+            }
+            State::Closed => return Err(io::Error::new(io::ErrorKind::BrokenPipe, "uTP closed")),
+            _ if connection.fin_sent => {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "uTP shut down"))
+            }
+            _ => {}
+        }
+        let mut written = 0;
+        while written < buf.len() {
+            let len = cmp::min(cmp::min(connection.available(), MAX_PAYLOAD_LEN), buf.len() - written);
+            if 0 == len {
+                break;
+            }
+            connection.send(&mut shared.outbox, ST_DATA, Vec::from(&buf[written..written + len]));
+            written += len;
+        }
+        shared.notify();
+        if 0 == written && !buf.is_empty() {
+            connection.writer = Some(task::current());
+            return Err(would_block());
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for UtpStream {}
+
+impl AsyncWrite for UtpStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.close();
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        self.close();
+        self.connection.borrow_mut().dropped = true;
+    }
+}
+
+/// returns true if a precedes b in the wrapping sequence space
+fn seq_less(a: u16, b: u16) -> bool {
+    (b.wrapping_sub(a) as i16) > 0
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+fn now_micros() -> u32 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    (now.as_secs() * 1_000_000 + (now.subsec_nanos() / 1000) as u64) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+    use tokio_core::reactor::Core;
+    use tokio_io::io::{read_to_end, shutdown, write_all};
+
+    fn data(len: u32, seed: u32) -> Vec<u8> {
+        (0..len).map(|i| (i * seed % 251) as u8).collect()
+    }
+
+    #[test]
+    fn loopback_transfer() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let localhost = "127.0.0.1:0".parse().unwrap();
+        let server = UtpSocket::bind(&localhost, &handle).unwrap();
+        let client = UtpSocket::bind(&localhost, &handle).unwrap();
+        let addr = server.local_addr().unwrap();
+        let upload = data(300000, 7);
+        let download = data(200000, 13);
+
+        let served = download.clone();
+        let accepted = server.incoming().into_future().map_err(|(err, _)| err).and_then(
+            move |(stream, _)| {
+                let (stream, _) = stream.unwrap();
+                read_to_end(stream, Vec::new()).and_then(move |(stream, received)| {
+                    write_all(stream, served)
+                        .and_then(|(stream, _)| shutdown(stream))
+                        .map(move |_| received)
+                })
+            },
+        );
+        let sent = upload.clone();
+        let connected = client
+            .connect(&addr)
+            .and_then(move |stream| write_all(stream, sent))
+            .and_then(|(stream, _)| shutdown(stream))
+            .and_then(|stream| read_to_end(stream, Vec::new()))
+            .map(|(_, received)| received);
+
+        let (uploaded, downloaded) = core.run(accepted.join(connected)).unwrap();
+        assert!(uploaded == upload);
+        assert!(downloaded == download);
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        assert!(seq_less(1, 2));
+        assert!(seq_less(0xffff, 0));
+        assert!(!seq_less(2, 1));
+        assert!(!seq_less(5, 5));
+    }
+}
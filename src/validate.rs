@@ -1,11 +1,10 @@
 use std::io;
 use tokio_service::{Service, NewService};
-use futures::Future;
-//use futures::future;
+use futures::{future, Future};
+use codec;
 use Message;
 use Messages;
-
-//const ERROR_MESSAGE: &'static str = "Was found malformed message";
+use PeerError;
 
 pub struct Validate<T> {
     pub inner: T,
@@ -24,12 +23,15 @@ where
 {
     type Request = Message;
     type Response = Messages;
-    type Error = io::Error;
-    type Future = Box<Future<Item = Messages, Error = io::Error>>;
+    type Error = PeerError;
+    type Future = Box<Future<Item = Messages, Error = PeerError>>;
 
 
     fn call(&self, req: Message) -> Self::Future {
-        Box::new(self.inner.call(req))
+        if let Err(err) = codec::validate(&req) {
+            return Box::new(future::err(err));
+        }
+        Box::new(self.inner.call(req).map_err(PeerError::from))
     }
 }
 
@@ -44,7 +46,7 @@ where
 {
     type Request = Message;
     type Response = Messages;
-    type Error = io::Error;
+    type Error = PeerError;
     type Instance = Validate<T::Instance>;
 
     fn new_service(&self) -> io::Result<Self::Instance> {
@@ -0,0 +1,118 @@
+use std::cmp;
+use std::time::{Duration, Instant};
+
+use metainfo::BLOCK_LEN;
+
+/// Requests in flight before the download rate is known
+const INITIAL_WINDOW: u32 = 4;
+const MIN_WINDOW: u32 = 2;
+const MAX_WINDOW: u32 = 250;
+/// Requests cover this much time of the download at the measured rate
+const QUEUE_TIME_MS: u64 = 3000;
+/// Download rate is measured over this interval
+const SAMPLE_MS: u64 = 1000;
+/// Peer is slow if no block arrives for this long while requests are in flight
+const STALL_MS: u64 = 10000;
+
+/// Number of the outstanding requests kept per peer, it grows while the peer keeps up
+/// and shrinks when the peer chokes us or goes slow
+pub struct RequestWindow {
+    size: u32,
+    min: u32,
+    max: u32,
+    /// smoothed download rate in bytes per second, unknown until the first sample
+    rate: Option<u64>,
+    /// bytes received since the start of the sample
+    bytes: u64,
+    sample_start: Instant,
+    last_block: Instant,
+}
+
+impl RequestWindow {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        RequestWindow {
+            size: INITIAL_WINDOW,
+            min: MIN_WINDOW,
+            max: MAX_WINDOW,
+            rate: None,
+            bytes: 0,
+            sample_start: now,
+            last_block: now,
+        }
+    }
+
+    /// sets the bounds of the window, the peer's reqq lowers the upper one further
+    pub fn set_limits(&mut self, min: u32, max: u32) {
+        self.min = cmp::max(1, min);
+        self.max = cmp::max(self.min, max);
+        self.size = cmp::max(self.min, cmp::min(self.max, self.size));
+    }
+
+    /// returns number of the requests which may be in flight,
+    /// reqq is the queue length announced by the peer
+    pub fn size(&self, reqq: Option<u32>) -> u32 {
+        let max = reqq.map_or(self.max, |reqq| cmp::max(1, cmp::min(self.max, reqq)));
+        cmp::min(self.size, max)
+    }
+
+    /// returns the smoothed download rate in bytes per second
+    pub fn rate(&self) -> Option<u64> {
+        self.rate
+    }
+
+    /// accounts the received block; until the rate is measured the window grows
+    /// by one request per block, then it follows the rate
+    pub fn received(&mut self, length: u32) {
+        let now = Instant::now();
+        self.last_block = now;
+        self.bytes += length as u64;
+        if self.rate.is_none() {
+            self.size = cmp::min(self.max, self.size + 1);
+        }
+        let elapsed = now.duration_since(self.sample_start);
+        if elapsed >= Duration::from_millis(SAMPLE_MS) {
+            let millis = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000;
+            let sample = self.bytes * 1000 / millis;
+            let rate = self.rate.map_or(sample, |rate| (rate + sample) / 2);
+            let size = rate * QUEUE_TIME_MS / 1000 / BLOCK_LEN as u64 + 1;
+            self.size = cmp::max(self.min, cmp::min(self.max as u64, size) as u32);
+            self.rate = Some(rate);
+            self.bytes = 0;
+            self.sample_start = now;
+        }
+    }
+
+    /// peer has choked us, it'll likely be slower after the unchoke
+    pub fn choked(&mut self) {
+        self.shrink();
+        self.bytes = 0;
+        self.sample_start = Instant::now();
+    }
+
+    /// outstanding requests have expired, the peer can't keep up with the window
+    pub fn expired(&mut self) {
+        self.shrink();
+    }
+
+    /// requests are sent after a pause, the stall is counted from now
+    pub fn resume(&mut self) {
+        self.last_block = Instant::now();
+    }
+
+    /// halves the window if no block has arrived for a while, returns true if it did
+    pub fn check_stall(&mut self) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.last_block) < Duration::from_millis(STALL_MS) {
+            return false;
+        }
+        self.shrink();
+        self.rate = self.rate.map(|rate| rate / 2);
+        self.last_block = now;
+        true
+    }
+
+    fn shrink(&mut self) {
+        self.size = cmp::max(self.min, self.size / 2);
+    }
+}